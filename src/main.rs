@@ -1,15 +1,17 @@
-use clap::{Parser, Subcommand};
-use serde_with::{StringWithSeparator, serde_as};
-use std::collections::HashMap;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use serde_with::{DeserializeAs, DisplayFromStr, SerializeAs, StringWithSeparator, serde_as};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
 use std::fs::File;
 use std::io::prelude::*;
 use std::net::{IpAddr, Ipv4Addr};
 use std::path::Path;
+use std::str::FromStr;
 use std::{cmp::min, fs, path::PathBuf};
 
-use rand::prelude::*;
-
-use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+use serde::de::{self, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde_with::formats::SemicolonSeparator;
 
 use anyhow::{Context, Result, anyhow};
@@ -19,7 +21,7 @@ use wireguard_keys::Privkey;
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 struct Cli {
-    /// csv file path
+    /// Nodes file path, csv, toml or yaml by extension
     #[arg(short, long, default_value = "mesh.csv")]
     filename: PathBuf,
 
@@ -27,6 +29,26 @@ struct Cli {
     #[arg(short, long, default_value = "nat.csv")]
     nat_filename: PathBuf,
 
+    /// Link costs csv file path, used when it exists
+    #[arg(long, default_value = "costs.csv")]
+    costs_filename: PathBuf,
+
+    /// Tenants csv file path, used when it exists
+    #[arg(long, default_value = "tenants.csv")]
+    tenants_filename: PathBuf,
+
+    /// Bridge ports csv file path, used when it exists
+    #[arg(long, default_value = "ports.csv")]
+    ports_filename: PathBuf,
+
+    /// Vlans csv file path, used when it exists
+    #[arg(long, default_value = "vlans.csv")]
+    vlans_filename: PathBuf,
+
+    /// Mesh settings toml file path, used when it exists (default: settings.toml next to the nodes file)
+    #[arg(long)]
+    settings_filename: Option<PathBuf>,
+
     /// Config files output folder
     #[arg(long)]
     output_folder: Option<PathBuf>,
@@ -43,123 +65,1980 @@ enum Commands {
     /// Generate missing private keys
     GenPrivkeys,
 
+    /// Convert the nodes file to another format, picked by extension (csv, toml, yaml)
+    Convert {
+        /// Converted file
+        output: PathBuf,
+    },
+
+    /// Add, remove or edit nodes, the nodes file is checked before saving
+    Node {
+        #[command(subcommand)]
+        command: NodeCommands,
+    },
+
     /// Check csv for duplicate and other configuration issues
-    Check,
+    Check {
+        #[command(flatten)]
+        vni: VniArgs,
+    },
 
-    /// Generate mikrotik config
+    /// Generate mikrotik config, the flags override the settings file
     GenConfig {
         /// The first ip to use for ptp links between wg peers
         #[arg(short, long)]
-        ptp_start_ip: IpAddr,
+        ptp_start_ip: Option<IpAddr>,
+
+        /// Features enabled unless switched off (default: l3-evpn)
+        #[arg(long, value_enum)]
+        profile: Option<Profile>,
 
         /// Use OSPF igp
-        #[arg(short, long, default_value_t = true)]
+        #[arg(short, long, overrides_with = "no_ospf")]
         ospf: bool,
 
-        /// Use EVPN with vxlan
-        #[arg(short, long, default_value_t = true)]
-        evpn: bool,
+        /// Don't use OSPF igp
+        #[arg(long, overrides_with = "ospf")]
+        no_ospf: bool,
+
+        /// Use EVPN with vxlan
+        #[arg(short, long, overrides_with = "no_evpn")]
+        evpn: bool,
+
+        /// Don't use EVPN, the nodes don't need vlans
+        #[arg(long, overrides_with = "evpn")]
+        no_evpn: bool,
+
+        /// AS number of the mesh (default: 65001)
+        #[arg(short, long)]
+        as_num: Option<u32>,
+
+        /// Anycast gateway vlans, overrides the vlans file
+        #[arg(short, long, value_delimiter = ',')]
+        vlans: Option<Vec<u16>>,
+
+        /// Anycast gateway addresses, overrides the vlans file
+        #[arg(long, value_delimiter = ',')]
+        anycast_addresses: Option<Vec<IpAddr>>,
+
+        /// DNAT support
+        #[arg(short, long, overrides_with = "no_dnat")]
+        dnat: bool,
+
+        /// No DNAT support
+        #[arg(long, overrides_with = "dnat")]
+        no_dnat: bool,
+
+        /// How the replies of connections dnatted by a peer find their way back to it (default: mac)
+        #[arg(long, value_enum)]
+        dnat_return: Option<DnatReturn>,
+
+        /// L2 mtu of the vxlan overlay, fragmented over the tunnels when too large (default: largest unfragmented mtu)
+        #[arg(long)]
+        l2_mtu: Option<u16>,
+
+        /// Use BFD on OSPF and BGP sessions
+        #[arg(long, overrides_with = "no_bfd")]
+        bfd: bool,
+
+        /// Don't use BFD
+        #[arg(long, overrides_with = "bfd")]
+        no_bfd: bool,
+
+        /// BFD min-rx and min-tx interval in milliseconds (default: 200)
+        #[arg(long)]
+        bfd_interval: Option<u32>,
+
+        /// BFD multiplier (default: 5)
+        #[arg(long)]
+        bfd_multiplier: Option<u8>,
+
+        /// Route reflectors cluster id (default: loopback of the first route reflector)
+        #[arg(long)]
+        cluster_id: Option<Ipv4Addr>,
+
+        /// Routing protocol between the loopbacks (default: ospf)
+        #[arg(long, value_enum)]
+        underlay: Option<Underlay>,
+
+        /// Accept the mesh traffic at the top of the input chain
+        #[arg(long, overrides_with = "no_firewall")]
+        firewall: bool,
+
+        /// Don't add the input chain rules
+        #[arg(long, overrides_with = "firewall")]
+        no_firewall: bool,
+
+        #[command(flatten)]
+        vni: VniArgs,
+    },
+
+    /// Creates DNAT csv file
+    NatInit,
+
+    /// Check the NAT csv for bad addresses, protocols, ports and node scopes
+    NatCheck,
+
+    /// Generate NAT config
+    NatGen {
+        /// Let the mesh vlans reach the forwarded services on their public address
+        #[arg(long, default_value_t = false)]
+        hairpin: bool,
+
+        /// Accept the forwarded connections in the forward chain
+        #[arg(long, default_value_t = false)]
+        filter: bool,
+    },
+
+    /// Generate port forwarding rules for the routers in front of the nodes
+    GenPortForwards,
+}
+
+#[derive(Subcommand)]
+enum NodeCommands {
+    /// Add a node with the next free loopback and port range and a new key
+    Add {
+        name: String,
+
+        /// Wireguard interface name of the node on its peers (default: the node name)
+        #[arg(long)]
+        interface: Option<String>,
+
+        #[arg(long)]
+        endpoint: Option<Endpoint>,
+
+        #[arg(long)]
+        endpoint_v6: Option<Endpoint>,
+
+        /// Loopback of the node (default: next free address of the loopback pool)
+        #[arg(long)]
+        loopback: Option<IpAddr>,
+
+        #[arg(long, default_value = "10.69.0.0/24")]
+        loopback_pool: String,
+
+        /// First listening port (default: after the ports of the other nodes)
+        #[arg(long)]
+        port_min: Option<u16>,
+
+        /// Listening ports, one per peer with room for the nodes added later
+        #[arg(long, default_value_t = 50)]
+        ports: u16,
+
+        #[arg(long, default_value_t = 25)]
+        keepalive: u64,
+    },
+
+    /// Remove a node
+    Remove { name: String },
+
+    /// Set fields of a node, empty values unset them
+    Set {
+        name: String,
+
+        /// field=value, lists are semicolon separated
+        #[arg(required = true)]
+        fields: Vec<String>,
+    },
+}
+
+#[derive(Args, Clone, Default)]
+struct VniArgs {
+    /// VNI of a vlan is this base plus the vlan id (default: vlan id prefixed with 1000)
+    #[arg(long)]
+    vni_base: Option<u32>,
+
+    /// Explicit vlan=vni mappings, take precedence over the base
+    #[arg(long, value_delimiter = ',', value_parser = parse_vni_mapping)]
+    vni_map: Vec<(u16, u32)>,
+
+    /// EVPN route targets (default: asn-vni)
+    #[arg(long, value_enum)]
+    route_targets: Option<RouteTargets>,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum RouteTargets {
+    /// <as-num>:<vni>
+    AsnVni,
+    /// Let RouterOS derive them
+    Auto,
+}
+
+fn parse_vni_mapping(s: &str) -> Result<(u16, u32)> {
+    let (vlan, vni) = s
+        .split_once('=')
+        .context(format!("{s}: expected vlan=vni"))?;
+    Ok((
+        vlan.parse().context(format!("{s}: invalid vlan {vlan}"))?,
+        vni.parse().context(format!("{s}: invalid vni {vni}"))?,
+    ))
+}
+
+/// Largest 24 bits vni
+const MAX_VNI: u32 = (1 << 24) - 1;
+
+impl VniArgs {
+    fn vni(&self, vlan: u16) -> Result<u32> {
+        if let Some((_, vni)) = self.vni_map.iter().find(|(v, _)| *v == vlan) {
+            return Ok(*vni);
+        }
+        match self.vni_base {
+            Some(base) => base
+                .checked_add(vlan as u32)
+                .context(format!("vlan {vlan}: vni overflow")),
+            None => Ok(format!("1000{vlan}").parse()?),
+        }
+    }
+
+    /// Every vlan needs its own vni that fits in 24 bits
+    fn check(&self, vlans: impl IntoIterator<Item = u16>) -> Result<()> {
+        let mut vnis: HashMap<u32, u16> = HashMap::new();
+        for vlan in vlans {
+            let vni = self.vni(vlan)?;
+            if vni == 0 || vni > MAX_VNI {
+                return Err(anyhow!("vlan {vlan}: vni {vni} is outside of 1-{MAX_VNI}"));
+            }
+            if let Some(other) = vnis.insert(vni, vlan)
+                && other != vlan
+            {
+                return Err(anyhow!("vlans {other} and {vlan} both use vni {vni}"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Flags first, then the settings file
+    fn with_settings(&self, settings: &Settings) -> Result<VniArgs> {
+        let mut vni_args = self.clone();
+        vni_args.vni_base = self.vni_base.or(settings.vni_base);
+        for mapping in settings.vni_map.iter().flatten() {
+            vni_args.vni_map.push(parse_vni_mapping(mapping)?);
+        }
+        vni_args.route_targets = self.route_targets.or(settings.route_targets);
+        Ok(vni_args)
+    }
+
+    /// Adds the vnis of the vlans file, the command line mappings take precedence
+    fn with_vlan_defs(&self, defs: &[VlanDef]) -> VniArgs {
+        let mut vni_args = self.clone();
+        vni_args.vni_map.extend(
+            defs.iter()
+                .filter_map(|def| def.vni.map(|vni| (def.id, vni))),
+        );
+        vni_args
+    }
+
+    fn route_targets(&self, as_num: u32, vni: u32) -> Result<String> {
+        match self.route_targets.unwrap_or(RouteTargets::AsnVni) {
+            RouteTargets::Auto => Ok(String::new()),
+            RouteTargets::AsnVni => {
+                // 2 bytes asn with 4 bytes value or 4 bytes asn with 2 bytes value
+                if as_num > u16::MAX as u32 && vni > u16::MAX as u32 {
+                    return Err(anyhow!(
+                        "route target {as_num}:{vni} doesn't fit, use a 2 bytes asn or --route-targets auto"
+                    ));
+                }
+                Ok(format!(
+                    " export.route-targets={as_num}:{vni} import.route-targets={as_num}:{vni}"
+                ))
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Profile {
+    /// Wireguard tunnels and routing between the loopbacks
+    UnderlayOnly,
+    /// Vlans stretched over vxlan, without gateways
+    L2Evpn,
+    /// Vlans stretched over vxlan with vlan addresses, anycast gateways, dhcp and tenant vrfs
+    L3Evpn,
+}
+
+/// Enabled switch, disabled switch or neither
+fn switch(on: bool, off: bool) -> Option<bool> {
+    match (on, off) {
+        (true, _) => Some(true),
+        (_, true) => Some(false),
+        _ => None,
+    }
+}
+
+/// Mesh wide settings of the generated configs, overridden by the flags
+#[derive(Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Settings {
+    ptp_start_ip: Option<IpAddr>,
+    profile: Option<Profile>,
+    ospf: Option<bool>,
+    evpn: Option<bool>,
+    as_num: Option<u32>,
+    vlans: Option<Vec<u16>>,
+    anycast_addresses: Option<Vec<IpAddr>>,
+    dnat: Option<bool>,
+    dnat_return: Option<DnatReturn>,
+    l2_mtu: Option<u16>,
+    bfd: Option<bool>,
+    bfd_interval: Option<u32>,
+    bfd_multiplier: Option<u8>,
+    cluster_id: Option<Ipv4Addr>,
+    underlay: Option<Underlay>,
+    firewall: Option<bool>,
+    vni_base: Option<u32>,
+    /// vlan=vni mappings
+    vni_map: Option<Vec<String>>,
+    route_targets: Option<RouteTargets>,
+}
+
+impl Cli {
+    fn settings_filename(&self) -> PathBuf {
+        self.settings_filename
+            .clone()
+            .unwrap_or_else(|| self.filename.with_file_name("settings.toml"))
+    }
+}
+
+fn read_settings(cli: &Cli) -> Result<Settings> {
+    let path = cli.settings_filename();
+    if !path.exists() {
+        return Ok(Settings::default());
+    }
+    let context = format!("Failed to read settings from {}", path.display());
+    toml::from_str(&fs::read_to_string(&path).context(context.clone())?).context(context)
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Underlay {
+    /// OSPF area over the ptp links
+    Ospf,
+    /// eBGP session over each ptp link, one ASN per node
+    Ebgp,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum DnatReturn {
+    /// Mark the connections bridged in from a peer by its bridge mac, needs the bridge firewall
+    Mac,
+    /// Mark the connections routed in over the tunnel of a peer, without the bridge firewall.
+    /// Only for lans that aren't stretched: a peer reaches the hosts of a stretched vlan over
+    /// the vxlan, their node only bridges those connections and needs the mac return path
+    Routed,
+}
+
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize)]
+struct Record {
+    name: String,
+    interface: String,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    endpoint: Option<Endpoint>,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    endpoint_v6: Option<Endpoint>,
+    loopback: IpAddr,
+    port_min: Option<u16>,
+    port_max: Option<u16>,
+    keepalive: Option<u64>,
+    privkey: Option<Privkey>,
+    lan_address: Option<IpAddr>,
+    underlay_mtu: Option<u16>,
+    region: Option<String>,
+    role: Option<BgpRole>,
+    asn: Option<u32>,
+    #[serde_as(as = "Option<SemicolonList>")]
+    tags: Option<Vec<String>>,
+    #[serde_as(as = "Option<SemicolonList>")]
+    vlan: Option<Vec<u16>>,
+    #[serde_as(as = "Option<SemicolonList>")]
+    vlan_ifs: Option<Vec<String>>,
+    #[serde_as(as = "Option<SemicolonList>")]
+    ifs_ips: Option<Vec<String>>,
+}
+
+/// Lists of the inventory, semicolon separated in csv cells and native lists in toml and yaml
+struct SemicolonList;
+
+impl<T: fmt::Display> SerializeAs<Vec<T>> for SemicolonList {
+    fn serialize_as<S: Serializer>(source: &Vec<T>, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(
+            &source
+                .iter()
+                .map(|item| item.to_string())
+                .collect::<Vec<_>>()
+                .join(";"),
+        )
+    }
+}
+
+impl<'de, T: FromStr> DeserializeAs<'de, Vec<T>> for SemicolonList
+where
+    T::Err: fmt::Display,
+{
+    fn deserialize_as<D: Deserializer<'de>>(deserializer: D) -> Result<Vec<T>, D::Error> {
+        struct ListVisitor<T>(std::marker::PhantomData<T>);
+
+        impl<'de, T: FromStr> Visitor<'de> for ListVisitor<T>
+        where
+            T::Err: fmt::Display,
+        {
+            type Value = Vec<T>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a list or a semicolon separated string")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                v.split(';')
+                    .filter(|item| !item.is_empty())
+                    .map(|item| item.parse().map_err(E::custom))
+                    .collect()
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<Self::Value, E> {
+                self.visit_str(&v.to_string())
+            }
+
+            fn visit_i64<E: de::Error>(self, v: i64) -> Result<Self::Value, E> {
+                self.visit_str(&v.to_string())
+            }
+
+            // The csv reader infers the type of each cell
+            fn visit_f64<E: de::Error>(self, v: f64) -> Result<Self::Value, E> {
+                self.visit_str(&v.to_string())
+            }
+
+            fn visit_bool<E: de::Error>(self, v: bool) -> Result<Self::Value, E> {
+                self.visit_str(&v.to_string())
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let mut items = vec![];
+                while let Some(item) = seq.next_element::<toml::Value>()? {
+                    let item = match item {
+                        toml::Value::String(item) => item,
+                        item => item.to_string(),
+                    };
+                    items.push(item.parse().map_err(de::Error::custom)?);
+                }
+                Ok(items)
+            }
+        }
+
+        deserializer.deserialize_any(ListVisitor(std::marker::PhantomData))
+    }
+}
+
+/// Fields of `Record` holding lists
+const LIST_FIELDS: [&str; 4] = ["tags", "vlan", "vlan_ifs", "ifs_ips"];
+
+#[derive(Clone, Copy, PartialEq)]
+enum InventoryFormat {
+    Csv,
+    Toml,
+    Yaml,
+}
+
+impl InventoryFormat {
+    /// Picked by file extension, csv by default
+    fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => InventoryFormat::Toml,
+            Some("yaml" | "yml") => InventoryFormat::Yaml,
+            _ => InventoryFormat::Csv,
+        }
+    }
+}
+
+/// Toml and yaml inventories hold the nodes under `nodes`
+#[derive(Serialize, Deserialize)]
+struct Inventory<T> {
+    nodes: Vec<T>,
+}
+
+fn read_records(path: &Path) -> Result<Vec<Record>> {
+    let context = format!("Failed to read nodes from {}", path.display());
+    match InventoryFormat::from_path(path) {
+        InventoryFormat::Csv => {
+            let mut rdr = csv::Reader::from_path(path).context(context)?;
+            Ok(rdr.deserialize().collect::<Result<Vec<_>, _>>()?)
+        }
+        InventoryFormat::Toml => {
+            let inventory: Inventory<Record> =
+                toml::from_str(&fs::read_to_string(path).context(context.clone())?)
+                    .context(context)?;
+            Ok(inventory.nodes)
+        }
+        InventoryFormat::Yaml => {
+            let inventory: Inventory<Record> =
+                serde_yaml_ng::from_str(&fs::read_to_string(path).context(context.clone())?)
+                    .context(context)?;
+            Ok(inventory.nodes)
+        }
+    }
+}
+
+fn write_records(path: &Path, records: &[Record]) -> Result<()> {
+    let context = format!("Failed to write nodes to {}", path.display());
+    let format = InventoryFormat::from_path(path);
+    if format == InventoryFormat::Csv {
+        let mut wtr = csv::Writer::from_path(path).context(context.clone())?;
+        for r in records {
+            wtr.serialize(r).context(context.clone())?;
+        }
+        return wtr.flush().context(context);
+    }
+
+    // Semicolon separated lists become native lists
+    let mut inventory = toml::Value::try_from(Inventory {
+        nodes: records.iter().collect(),
+    })?;
+    for node in inventory
+        .get_mut("nodes")
+        .and_then(|nodes| nodes.as_array_mut())
+        .into_iter()
+        .flatten()
+    {
+        for field in LIST_FIELDS {
+            if let Some(value) = node.get_mut(field)
+                && let Some(list) = value.as_str()
+            {
+                *value = toml::Value::Array(
+                    list.split(';')
+                        .map(|item| match item.parse::<i64>() {
+                            Ok(n) => toml::Value::Integer(n),
+                            Err(_) => toml::Value::String(item.to_owned()),
+                        })
+                        .collect(),
+                );
+            }
+        }
+    }
+    let content = match format {
+        InventoryFormat::Yaml => serde_yaml_ng::to_string(&inventory)?,
+        _ => toml::to_string(&inventory)?,
+    };
+    fs::write(path, content).context(context)
+}
+
+/// Mesh wide vlan settings
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize)]
+struct VlanDef {
+    id: u16,
+    name: Option<String>,
+    subnet: Option<String>,
+    anycast_gateway: Option<String>,
+    /// Anycast gateway mac, derived from the vlan id when not set
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    mac: Option<macaddr::MacAddr6>,
+    vni: Option<u32>,
+    /// Serve dhcp on every node carrying the vlan, each from its own slice of the subnet
+    dhcp: Option<bool>,
+    #[serde_as(as = "Option<StringWithSeparator::<SemicolonSeparator, IpAddr>>")]
+    dns_servers: Option<Vec<IpAddr>>,
+    lease_time: Option<String>,
+}
+
+/// Anycast gateway of a vlan, the same on every node carrying it
+struct AnycastGateway {
+    vlan: u16,
+    address: String,
+    mac: macaddr::MacAddr6,
+}
+
+/// Routed tenant, its vlans are put in a vrf and routed between the nodes
+/// over the l3 vni (symmetric IRB)
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize)]
+struct Tenant {
+    name: String,
+    #[serde_as(as = "StringWithSeparator::<SemicolonSeparator, u16>")]
+    vlans: Vec<u16>,
+    l3vni: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum PortMode {
+    /// Untagged member of `pvid`
+    Access,
+    /// Tagged member of `vlans`
+    Trunk,
+    /// Untagged member of `pvid` and tagged member of `vlans`
+    Hybrid,
+}
+
+/// Mesh bridge port of a node, nodes without ports use `vlan_ifs` as access ports
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BridgePort {
+    node: String,
+    interface: String,
+    mode: PortMode,
+    pvid: Option<u16>,
+    #[serde_as(as = "Option<StringWithSeparator::<SemicolonSeparator, u16>>")]
+    vlans: Option<Vec<u16>>,
+}
+
+impl BridgePort {
+    fn tagged(&self, vlan: u16) -> bool {
+        self.mode != PortMode::Access && self.vlans.iter().flatten().any(|v| *v == vlan)
+    }
+
+    fn untagged(&self, vlan: u16) -> bool {
+        self.mode != PortMode::Trunk && self.pvid == Some(vlan)
+    }
+}
+
+/// iBGP role of a node, a full mesh is used when there are no route reflectors
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum BgpRole {
+    RouteReflector,
+    Client,
+}
+
+/// OSPF cost between two nodes or two regions, `latency` in ms is used as the
+/// cost when no explicit cost is set
+#[derive(Debug, Serialize, Deserialize)]
+struct LinkCost {
+    a: String,
+    b: String,
+    cost: Option<u16>,
+    latency: Option<u32>,
+}
+
+impl LinkCost {
+    fn value(&self) -> Option<u16> {
+        self.cost.or(self
+            .latency
+            .map(|latency| latency.clamp(1, u16::MAX as u32) as u16))
+    }
+
+    fn connects(&self, a: &str, b: &str) -> bool {
+        (self.a == a && self.b == b) || (self.a == b && self.b == a)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum EndpointHost {
+    Ip(IpAddr),
+    Hostname(String),
+}
+
+/// Underlay address of a node, `port` is the public port matching `port_min`
+/// when the listen ports are translated on the way in
+#[derive(Debug, Clone, PartialEq)]
+struct Endpoint {
+    host: EndpointHost,
+    port: Option<u16>,
+}
+
+impl Endpoint {
+    fn is_v4(&self) -> bool {
+        matches!(self.host, EndpointHost::Ip(IpAddr::V4(_)))
+    }
+
+    /// Port peers have to connect to for a given listen port, none when it's past 65535
+    fn public_port(&self, listen_port: u16, port_min: u16) -> Option<u16> {
+        self.port.map_or(Some(listen_port), |port| {
+            port.checked_add(listen_port.checked_sub(port_min)?)
+        })
+    }
+}
+
+impl fmt::Display for EndpointHost {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EndpointHost::Ip(ip) => write!(f, "{ip}"),
+            EndpointHost::Hostname(hostname) => write!(f, "{hostname}"),
+        }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.host, self.port) {
+            (EndpointHost::Ip(IpAddr::V6(ip)), Some(port)) => write!(f, "[{ip}]:{port}"),
+            (host, Some(port)) => write!(f, "{host}:{port}"),
+            (host, None) => write!(f, "{host}"),
+        }
+    }
+}
+
+impl FromStr for EndpointHost {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Ok(ip) = s.parse() {
+            return Ok(EndpointHost::Ip(ip));
+        }
+        let valid_label = |l: &str| {
+            !l.is_empty()
+                && l.len() <= 63
+                && !l.starts_with('-')
+                && !l.ends_with('-')
+                && l.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        };
+        let hostname = s.strip_suffix('.').unwrap_or(s);
+        let tld = hostname.rsplit('.').next().unwrap_or_default();
+        if hostname.len() > 253
+            || !hostname.split('.').all(valid_label)
+            || tld.chars().all(|c| c.is_ascii_digit())
+        {
+            return Err(anyhow!("{s} is not a valid ip address or hostname"));
+        }
+        Ok(EndpointHost::Hostname(hostname.to_owned()))
+    }
+}
+
+impl FromStr for Endpoint {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parse_port = |port: &str| {
+            port.parse::<u16>()
+                .map_err(|_| anyhow!("{s}: invalid endpoint port {port}"))
+        };
+        // [v6]:port
+        if let Some(rest) = s.strip_prefix('[') {
+            let (ip, port) = rest
+                .split_once(']')
+                .context(format!("{s}: missing closing bracket"))?;
+            let ip = ip
+                .parse()
+                .map_err(|_| anyhow!("{s}: {ip} is not a valid ipv6 address"))?;
+            let port = match port {
+                "" => None,
+                port => Some(parse_port(
+                    port.strip_prefix(':')
+                        .context(format!("{s}: expected :port after ]"))?,
+                )?),
+            };
+            return Ok(Endpoint {
+                host: EndpointHost::Ip(IpAddr::V6(ip)),
+                port,
+            });
+        }
+        // Bare v6 addresses can't have a port
+        if let Ok(ip) = s.parse() {
+            return Ok(Endpoint {
+                host: EndpointHost::Ip(ip),
+                port: None,
+            });
+        }
+        match s.rsplit_once(':') {
+            Some((host, port)) => Ok(Endpoint {
+                host: host.parse()?,
+                port: Some(parse_port(port)?),
+            }),
+            None => Ok(Endpoint {
+                host: s.parse()?,
+                port: None,
+            }),
+        }
+    }
+}
+
+/// Port list with ranges, `80;443;8000-8080`
+#[derive(Debug, Clone, PartialEq)]
+struct Ports(Vec<(u16, u16)>);
+
+impl fmt::Display for Ports {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ports = self
+            .0
+            .iter()
+            .map(|(start, end)| {
+                if start == end {
+                    start.to_string()
+                } else {
+                    format!("{start}-{end}")
+                }
+            })
+            .collect::<Vec<_>>();
+        write!(f, "{}", ports.join(","))
+    }
+}
+
+impl FromStr for Ports {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parse_port = |port: &str| {
+            port.trim()
+                .parse::<u16>()
+                .map_err(|_| anyhow!("{s}: invalid port {port}"))
+        };
+        let ports = s
+            .split([';', ','])
+            .map(|range| {
+                let (start, end) = match range.split_once('-') {
+                    Some((start, end)) => (parse_port(start)?, parse_port(end)?),
+                    None => (parse_port(range)?, parse_port(range)?),
+                };
+                if start > end {
+                    return Err(anyhow!("{s}: invalid port range {range}"));
+                }
+                Ok((start, end))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Ports(ports))
+    }
+}
+
+/// Protocols with ports
+const PORT_PROTOCOLS: [&str; 5] = ["tcp", "udp", "udp-lite", "sctp", "dccp"];
+
+/// Protocols names accepted by RouterOS, numbers are also allowed
+const PROTOCOLS: [&str; 26] = [
+    "dccp",
+    "ddp",
+    "egp",
+    "encap",
+    "etherip",
+    "ggp",
+    "gre",
+    "hmp",
+    "icmp",
+    "icmpv6",
+    "idpr-cmtp",
+    "igmp",
+    "ipencap",
+    "ipip",
+    "ipsec-ah",
+    "ipsec-esp",
+    "ipv6-encap",
+    "ipv6-frag",
+    "ipv6-nonxt",
+    "ipv6-opts",
+    "ipv6-route",
+    "iso-tp4",
+    "l2tp",
+    "ospf",
+    "pim",
+    "rspf",
+];
+
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize)]
+struct SimpleNat {
+    comment: String,
+    dest_ip: IpAddr,
+    protocol: Option<String>,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    dest_port: Option<Ports>,
+    rewrite_ip: IpAddr,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    rewrite_port: Option<Ports>,
+    #[serde(default)]
+    src_address: Option<String>,
+    #[serde(default)]
+    src_address_list: Option<String>,
+    #[serde(default)]
+    in_interface: Option<String>,
+    #[serde(default)]
+    in_interface_list: Option<String>,
+    /// Names or tags of the nodes the rule is deployed on, every node when empty
+    #[serde_as(as = "Option<StringWithSeparator::<SemicolonSeparator, String>>")]
+    nodes: Option<Vec<String>>,
+}
+
+impl SimpleNat {
+    fn check(&self) -> Result<()> {
+        if let Some(protocol) = &self.protocol
+            && !PORT_PROTOCOLS.contains(&protocol.as_str())
+            && !PROTOCOLS.contains(&protocol.as_str())
+            && protocol.parse::<u8>().is_err()
+        {
+            return Err(anyhow!("{}: unknown protocol {protocol}", self.comment));
+        }
+        if (self.dest_port.is_some() || self.rewrite_port.is_some())
+            && !self
+                .protocol
+                .as_ref()
+                .is_some_and(|p| PORT_PROTOCOLS.contains(&p.as_str()))
+        {
+            return Err(anyhow!(
+                "{}: ports need one of the {} protocols",
+                self.comment,
+                PORT_PROTOCOLS.join(", ")
+            ));
+        }
+        if let Some(rewrite_port) = &self.rewrite_port
+            && rewrite_port.0.len() > 1
+        {
+            return Err(anyhow!(
+                "{}: rewrite_port can only be a single port or range",
+                self.comment
+            ));
+        }
+        if let Some(src_address) = &self.src_address
+            && src_address.parse::<IpAddr>().is_err()
+        {
+            parse_prefix(src_address).context(format!("{}: invalid src_address", self.comment))?;
+        }
+        Ok(())
+    }
+
+    /// Source matchers of the connection, with or without the in-interface matchers
+    fn src_matchers(&self, in_interface: bool) -> String {
+        let mut matchers = vec![
+            ("src-address", &self.src_address),
+            ("src-address-list", &self.src_address_list),
+        ];
+        if in_interface {
+            matchers.push(("in-interface", &self.in_interface));
+            matchers.push(("in-interface-list", &self.in_interface_list));
+        }
+        matchers
+            .into_iter()
+            .filter_map(|(field, value)| value.as_ref().map(|value| format!(" {field}={value}")))
+            .collect()
+    }
+
+    /// Matchers and rewrite of the dst-nat rule, with or without the in-interface matchers
+    fn dst_nat(&self, in_interface: bool) -> String {
+        let mut rule = format!(
+            "dst-address={} to-addresses={}",
+            self.dest_ip, self.rewrite_ip
+        );
+        if let Some(protocol) = &self.protocol {
+            rule.push_str(&format!(" protocol={protocol}"));
+        }
+        if let Some(rewrite_port) = &self.rewrite_port {
+            rule.push_str(&format!(" to-ports={rewrite_port}"));
+        }
+        if let Some(dest_port) = &self.dest_port {
+            rule.push_str(&format!(" dst-port={dest_port}"));
+        }
+        rule.push_str(&self.src_matchers(in_interface));
+        rule
+    }
+
+    /// Matchers of the forwarded connections, with or without the in-interface matchers
+    fn forward_filter(&self, in_interface: bool) -> String {
+        format!(
+            "connection-nat-state=dstnat dst-address={}{}{}",
+            self.rewrite_ip,
+            self.rewritten_ports(),
+            self.src_matchers(in_interface)
+        )
+    }
+
+    /// Protocol and ports of the connections once rewritten
+    fn rewritten_ports(&self) -> String {
+        let mut rule = String::new();
+        if let Some(protocol) = &self.protocol {
+            rule.push_str(&format!(" protocol={protocol}"));
+        }
+        if let Some(port) = self.rewrite_port.as_ref().or(self.dest_port.as_ref()) {
+            rule.push_str(&format!(" dst-port={port}"));
+        }
+        rule
+    }
+}
+
+/// Source nat, masquerades out of `out_interface` when `action` is masquerade
+/// or rewrites to `to_address` with src-nat
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize)]
+struct SourceNat {
+    comment: String,
+    action: String,
+    src_address: String,
+    #[serde(default)]
+    out_interface: Option<String>,
+    #[serde(default)]
+    out_interface_list: Option<String>,
+    #[serde(default)]
+    to_address: Option<IpAddr>,
+    /// Names or tags of the nodes the rule is deployed on, every node when empty
+    #[serde_as(as = "Option<StringWithSeparator::<SemicolonSeparator, String>>")]
+    nodes: Option<Vec<String>>,
+}
+
+impl SourceNat {
+    fn check(&self) -> Result<()> {
+        if self.src_address.parse::<IpAddr>().is_err() {
+            parse_prefix(&self.src_address)
+                .context(format!("{}: invalid src_address", self.comment))?;
+        }
+        match (self.action.as_str(), self.to_address) {
+            ("masquerade", None) | ("src-nat", Some(_)) => Ok(()),
+            ("masquerade", Some(_)) => Err(anyhow!(
+                "{}: masquerade doesn't take a to_address",
+                self.comment
+            )),
+            ("src-nat", None) => Err(anyhow!("{}: src-nat needs a to_address", self.comment)),
+            (action, _) => Err(anyhow!("{}: unknown action {action}", self.comment)),
+        }
+    }
+
+    fn rule(&self) -> String {
+        let mut rule = format!(
+            "action={} chain=srcnat src-address={}",
+            self.action, self.src_address
+        );
+        for (field, value) in [
+            ("out-interface", &self.out_interface),
+            ("out-interface-list", &self.out_interface_list),
+        ] {
+            if let Some(value) = value {
+                rule.push_str(&format!(" {field}={value}"));
+            }
+        }
+        if let Some(to_address) = self.to_address {
+            rule.push_str(&format!(" to-addresses={to_address}"));
+        }
+        rule
+    }
+}
+
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize)]
+struct CustomNat {
+    comment: String,
+    custom_cmd: String,
+    /// Names or tags of the nodes the rule is deployed on, every node when empty
+    #[serde_as(as = "Option<StringWithSeparator::<SemicolonSeparator, String>>")]
+    nodes: Option<Vec<String>>,
+}
+
+/// Row of the nat csv, the `type` column picks the columns that follow
+#[derive(Debug)]
+enum NatRecord {
+    Simple(SimpleNat),
+    Source(SourceNat),
+    Custom(CustomNat),
+}
+
+/// Record types of the nat csv with their largest number of columns after `type`,
+/// the trailing optional columns can be left out
+const NAT_TYPES: [(&str, usize); 3] = [("simple", 11), ("snat", 7), ("custom", 3)];
+
+impl NatRecord {
+    fn kind(&self) -> &str {
+        match self {
+            NatRecord::Simple(_) => "simple",
+            NatRecord::Source(_) => "snat",
+            NatRecord::Custom(_) => "custom",
+        }
+    }
+
+    fn nodes(&self) -> &Option<Vec<String>> {
+        match self {
+            NatRecord::Simple(simple_nat) => &simple_nat.nodes,
+            NatRecord::Source(source_nat) => &source_nat.nodes,
+            NatRecord::Custom(custom_nat) => &custom_nat.nodes,
+        }
+    }
+
+    fn comment(&self) -> &str {
+        match self {
+            NatRecord::Simple(simple_nat) => &simple_nat.comment,
+            NatRecord::Source(source_nat) => &source_nat.comment,
+            NatRecord::Custom(custom_nat) => &custom_nat.comment,
+        }
+    }
+
+    /// Whether the rule is deployed on a node, matching its name or one of its tags
+    fn applies_to(&self, r: &Record) -> bool {
+        self.nodes().as_ref().is_none_or(|nodes| {
+            nodes
+                .iter()
+                .any(|n| *n == r.name || r.tags.iter().flatten().any(|tag| tag == n))
+        })
+    }
+}
+
+fn read_nat_records(cli: &Cli) -> Result<Vec<NatRecord>> {
+    let mut rdr = csv::ReaderBuilder::new()
+        .flexible(true)
+        .from_path(cli.nat_filename.clone())
+        .context(format!(
+            "Failed to read csv from {}",
+            cli.nat_filename.display()
+        ))?;
+
+    let mut nat_records = vec![];
+    for r in rdr.records() {
+        let r = r?;
+        let line = r.position().map(|p| p.line()).unwrap_or_default();
+        let context = format!("{}:{}", cli.nat_filename.display(), line);
+        let kind = r.get(0).unwrap_or_default();
+        let Some((_, columns)) = NAT_TYPES.iter().find(|(t, _)| *t == kind) else {
+            return Err(anyhow!(
+                "{context} unknown record type \"{kind}\", expected one of {}",
+                NAT_TYPES.map(|(t, _)| t).join(", ")
+            ));
+        };
+        if r.len() - 1 > *columns {
+            return Err(anyhow!(
+                "{context} {kind} records have at most {columns} columns after the type, found {}",
+                r.len() - 1
+            ));
+        }
+        let fields: csv::StringRecord = r.iter().skip(1).collect();
+        let nat_record = match kind {
+            "simple" => {
+                let n: SimpleNat = fields.deserialize(None).context(context.clone())?;
+                n.check().context(context)?;
+                NatRecord::Simple(n)
+            }
+            "snat" => {
+                let n: SourceNat = fields.deserialize(None).context(context.clone())?;
+                n.check().context(context)?;
+                NatRecord::Source(n)
+            }
+            _ => NatRecord::Custom(fields.deserialize(None).context(context)?),
+        };
+        nat_records.push(nat_record);
+    }
+    Ok(nat_records)
+}
+
+/// Rules have to be scoped to known nodes or tags
+fn check_nat_records(nat_records: &[NatRecord], records: &[Record]) -> Result<()> {
+    for n in nat_records {
+        for node in n.nodes().iter().flatten() {
+            if !records
+                .iter()
+                .any(|r| r.name == *node || r.tags.iter().flatten().any(|tag| tag == node))
+            {
+                return Err(anyhow!("{}: {node} isn't a known node or tag", n.comment()));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn export_configs(cli: &Cli, configs: HashMap<String, String>) -> Result<(), anyhow::Error> {
+    match &cli.output_folder {
+        None => {
+            for (node, config) in configs {
+                println!("{node}:\n{config}");
+            }
+            Ok(())
+        }
+        Some(output_folder) => {
+            let path = Path::new(output_folder);
+            if path.exists() && !path.is_dir() {
+                return Err(anyhow::anyhow!(format!(
+                    "A file named {} already exists",
+                    output_folder.display()
+                )));
+            }
+            if !path.is_dir() {
+                fs::create_dir(output_folder)?;
+            }
+
+            for (node, config) in &configs {
+                let mut filepath = output_folder.clone();
+                filepath.push(format!("{node}.rsc"));
+                let mut file = File::create(filepath)?;
+                file.write_all(config.as_bytes())?;
+            }
+            println!("wrote {} configs", configs.len());
+            Ok(())
+        }
+    }
+}
+
+/// Listen port used by each side of every tunnel
+/// ((local_peer, remote_peer), port)
+fn assign_ports(records: &[Record]) -> Result<HashMap<(String, String), u16>> {
+    let mut port_assignations = HashMap::new();
+    for server in records {
+        let mut port = Some(
+            server
+                .port_min
+                .context(format!("{}: no min port set", server.name))?,
+        );
+        for peer in records {
+            if server.name == peer.name {
+                continue;
+            }
+            let listen_port = port.context(format!(
+                "{}: not enough listening ports above port_min",
+                server.name
+            ))?;
+            port_assignations.insert((server.name.clone(), peer.name.clone()), listen_port);
+            port = listen_port.checked_add(1);
+        }
+    }
+    Ok(port_assignations)
+}
+
+/// Whether the tunnel between two nodes runs over the v6 underlay
+fn uses_v6_underlay(a: &Record, b: &Record) -> bool {
+    a.endpoint_v6.is_some() && b.endpoint_v6.is_some()
+}
+
+/// Endpoint `local` has to connect to to reach `peer`
+fn peer_endpoint<'a>(local: &Record, peer: &'a Record) -> Option<&'a Endpoint> {
+    if uses_v6_underlay(local, peer) {
+        peer.endpoint_v6.as_ref()
+    } else {
+        peer.endpoint.as_ref()
+    }
+}
+
+/// Default mtu of the network the tunnels run over
+const DEFAULT_UNDERLAY_MTU: u16 = 1500;
+
+/// Smallest underlay mtu, the ipv6 minimum, the tunnel overheads fit in it
+const MIN_UNDERLAY_MTU: u16 = 1280;
+
+/// WireGuard interface mtu of the tunnel between two nodes
+fn wg_mtu(a: &Record, b: &Record) -> u16 {
+    // outer ip header + udp (8) + wireguard (32)
+    const WG_OVERHEAD_V4: u16 = 20 + 8 + 32;
+    const WG_OVERHEAD_V6: u16 = 40 + 8 + 32;
+
+    let v4 = !uses_v6_underlay(a, b)
+        && [a, b]
+            .iter()
+            .all(|r| r.endpoint.as_ref().is_none_or(|e| e.is_v4()));
+    let underlay_mtu = min(
+        a.underlay_mtu.unwrap_or(DEFAULT_UNDERLAY_MTU),
+        b.underlay_mtu.unwrap_or(DEFAULT_UNDERLAY_MTU),
+    );
+    // Hostnames could resolve to either family
+    underlay_mtu - if v4 { WG_OVERHEAD_V4 } else { WG_OVERHEAD_V6 }
+}
+
+/// Largest vxlan mtu that fits in every tunnel of the mesh
+fn vxlan_mtu(records: &[Record]) -> u16 {
+    // ethernet (14) + vxlan (8) + udp (8) + ip header between loopbacks
+    let overhead = 14
+        + 8
+        + 8
+        + if records.iter().all(|r| r.loopback.is_ipv4()) {
+            20
+        } else {
+            40
+        };
+    records
+        .iter()
+        .enumerate()
+        .flat_map(|(i, a)| records.iter().skip(i + 1).map(move |b| wg_mtu(a, b)))
+        .min()
+        .unwrap_or(DEFAULT_UNDERLAY_MTU)
+        - overhead
+}
+
+/// Rows of a side csv file, none when the file doesn't exist
+fn read_optional_csv<T: DeserializeOwned>(path: &Path) -> Result<Vec<T>> {
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    let mut rdr = csv::Reader::from_path(path)
+        .context(format!("Failed to read csv from {}", path.display()))?;
+    Ok(rdr.deserialize().collect::<Result<Vec<_>, _>>()?)
+}
+
+/// Error pointing at a line of a csv file
+fn line_error(
+    path: &Path,
+    line: usize,
+    subject: impl fmt::Display,
+    msg: impl fmt::Display,
+) -> anyhow::Error {
+    anyhow!("{}:{line} {subject}: {msg}", path.display())
+}
+
+fn read_link_costs(cli: &Cli) -> Result<Vec<LinkCost>> {
+    read_optional_csv(&cli.costs_filename)
+}
+
+fn read_tenants(cli: &Cli) -> Result<Vec<Tenant>> {
+    read_optional_csv(&cli.tenants_filename)
+}
+
+fn read_vlan_defs(cli: &Cli) -> Result<Vec<VlanDef>> {
+    read_optional_csv(&cli.vlans_filename)
+}
+
+/// Splits an address/prefix
+fn parse_prefix(s: &str) -> Result<(IpAddr, u8)> {
+    let (ip, prefix) = s
+        .split_once('/')
+        .context(format!("{s} doesn't have netmask"))?;
+    let ip: IpAddr = ip
+        .parse()
+        .map_err(|_| anyhow!("{s}: invalid address {ip}"))?;
+    let prefix: u8 = prefix
+        .parse()
+        .map_err(|_| anyhow!("{s}: invalid netmask {prefix}"))?;
+    if prefix > if ip.is_ipv4() { 32 } else { 128 } {
+        return Err(anyhow!("{s}: invalid netmask {prefix}"));
+    }
+    Ok((ip, prefix))
+}
+
+fn in_subnet(ip: IpAddr, (network, prefix): (IpAddr, u8)) -> bool {
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(network)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
+
+/// Dhcp ranges of each node sharing a subnet, the hosts of the subnet are split
+/// in one slice per node and the reserved addresses are left out
+fn dhcp_ranges(
+    (network, prefix): (IpAddr, u8),
+    nodes: usize,
+    reserved: &[IpAddr],
+) -> Result<Vec<Vec<(Ipv4Addr, Ipv4Addr)>>> {
+    let IpAddr::V4(network) = network else {
+        return Err(anyhow!("dhcp needs an ipv4 subnet"));
+    };
+    if prefix > 30 {
+        return Err(anyhow!("{network}/{prefix} is too small for dhcp"));
+    }
+    if prefix < 16 {
+        return Err(anyhow!("{network}/{prefix} is too large for dhcp"));
+    }
+    if nodes == 0 {
+        return Err(anyhow!("{network}/{prefix} has no node to serve it"));
+    }
+    let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+    let first = (u32::from(network) & mask) + 1;
+    let last = (u32::from(network) | !mask) - 1;
+    let size = (last - first + 1) / nodes as u32;
+    if size == 0 {
+        return Err(anyhow!(
+            "{network}/{prefix} is too small to be split between {nodes} nodes"
+        ));
+    }
+    let reserved = reserved
+        .iter()
+        .filter_map(|ip| match ip {
+            IpAddr::V4(ip) => Some(u32::from(*ip)),
+            IpAddr::V6(_) => None,
+        })
+        .collect::<Vec<_>>();
+
+    Ok((0..nodes as u32)
+        .map(|n| {
+            let mut ranges = vec![];
+            let mut start = None;
+            for ip in first + n * size..first + (n + 1) * size {
+                match (reserved.contains(&ip), start) {
+                    (false, None) => start = Some(ip),
+                    (true, Some(s)) => {
+                        ranges.push((Ipv4Addr::from(s), Ipv4Addr::from(ip - 1)));
+                        start = None;
+                    }
+                    _ => {}
+                }
+            }
+            if let Some(s) = start {
+                ranges.push((
+                    Ipv4Addr::from(s),
+                    Ipv4Addr::from(first + (n + 1) * size - 1),
+                ));
+            }
+            ranges
+        })
+        .collect())
+}
+
+/// Network of an address/prefix
+fn network((ip, prefix): (IpAddr, u8)) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => IpAddr::V4(Ipv4Addr::from(
+            u32::from(ip) & u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0),
+        )),
+        IpAddr::V6(ip) => IpAddr::V6(std::net::Ipv6Addr::from(
+            u128::from(ip) & u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0),
+        )),
+    }
+}
+
+/// Subnets of the vlan addresses of every node
+fn vlan_subnets(records: &[Record]) -> Result<Vec<String>> {
+    let mut subnets = vec![];
+    for ip in records.iter().flat_map(|r| r.ifs_ips.iter().flatten()) {
+        let (ip, prefix) = parse_prefix(ip)?;
+        let subnet = format!("{}/{prefix}", network((ip, prefix)));
+        if !subnets.contains(&subnet) {
+            subnets.push(subnet);
+        }
+    }
+    Ok(subnets)
+}
+
+/// Bridge mac derived from the loopback, stable across generations. Its 0a
+/// prefix keeps it apart from the 02 anycast macs
+fn bridge_mac(loopback: IpAddr) -> macaddr::MacAddr6 {
+    let [a, b, c, d] = match loopback {
+        IpAddr::V4(ip) => ip.octets(),
+        IpAddr::V6(ip) => ip.octets()[12..].try_into().unwrap(),
+    };
+    macaddr::MacAddr6::new(0x0a, 0x6d, a, b, c, d)
+}
+
+/// Anycast gateway mac derived from the vlan id, locally administered unicast
+fn anycast_mac(vlan: u16) -> macaddr::MacAddr6 {
+    let [hi, lo] = vlan.to_be_bytes();
+    macaddr::MacAddr6::new(0x02, 0x6d, 0x77, 0x67, hi, lo)
+}
+
+/// Vlans need a unique id and anycast gateways inside their subnet
+fn check_vlan_defs(cli: &Cli, defs: &[VlanDef], records: &[Record]) -> Result<()> {
+    let mut ids = HashMap::new();
+    let mut macs = HashMap::new();
+    for (i, def) in (2..).zip(defs) {
+        let error =
+            |msg: String| line_error(&cli.vlans_filename, i, format!("vlan {}", def.id), msg);
+        if let Some(line) = ids.insert(def.id, i) {
+            return Err(error(format!("duplicate vlan found on line {line}")));
+        }
+        let subnet = def.subnet.as_deref().map(parse_prefix).transpose()?;
+        if let Some(gateway) = &def.anycast_gateway {
+            let (ip, _) = parse_prefix(gateway)?;
+            if let Some(subnet) = subnet
+                && !in_subnet(ip, subnet)
+            {
+                return Err(error(format!(
+                    "anycast gateway {gateway} isn't in the vlan subnet"
+                )));
+            }
+        }
+        if def.dhcp == Some(true) {
+            match subnet {
+                Some((IpAddr::V4(_), _)) => {}
+                _ => return Err(error("dhcp needs an ipv4 subnet".to_owned())),
+            }
+            if def.anycast_gateway.is_none() {
+                return Err(error("dhcp needs an anycast gateway".to_owned()));
+            }
+        }
+        if let Some(mac) = def.mac
+            && mac.as_bytes()[0] & 0x01 != 0
+        {
+            return Err(error(format!(
+                "anycast gateway mac {mac} is a multicast mac"
+            )));
+        }
+        let mac = def.mac.unwrap_or_else(|| anycast_mac(def.id));
+        if let Some(line) = macs.insert(mac, i) {
+            return Err(error(format!(
+                "anycast gateway mac {mac} is already used on line {line}"
+            )));
+        }
+        if let Some(r) = records.iter().find(|r| bridge_mac(r.loopback) == mac) {
+            return Err(error(format!(
+                "anycast gateway mac {mac} is the bridge mac of {}",
+                r.name
+            )));
+        }
+        if !records.iter().any(|r| carries_vlan(r, def.id)) {
+            println!("warning: vlan {} isn't carried by any node", def.id);
+        }
+    }
+    Ok(())
+}
+
+/// Anycast gateways from the vlans file, the command line vlans and addresses override them
+fn anycast_gateways(
+    defs: &[VlanDef],
+    vlans: &Option<Vec<u16>>,
+    addrs: &Option<Vec<IpAddr>>,
+) -> Result<Vec<AnycastGateway>> {
+    let mac = |vlan: u16| {
+        defs.iter()
+            .find(|def| def.id == vlan)
+            .and_then(|def| def.mac)
+            .unwrap_or_else(|| anycast_mac(vlan))
+    };
+    let mut gateways = defs
+        .iter()
+        .filter_map(|def| {
+            def.anycast_gateway.as_ref().map(|address| AnycastGateway {
+                vlan: def.id,
+                address: address.clone(),
+                mac: mac(def.id),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    if let Some(vlans) = vlans
+        && let Some(addrs) = addrs
+    {
+        if vlans.len() != addrs.len() {
+            return Err(anyhow!(
+                "Numbers of vlans and anycast addresses don't match"
+            ));
+        }
+        for (vlan, addr) in vlans.iter().zip(addrs) {
+            gateways.retain(|g| g.vlan != *vlan);
+            gateways.push(AnycastGateway {
+                vlan: *vlan,
+                address: addr.to_string(),
+                mac: mac(*vlan),
+            });
+        }
+    }
+    Ok(gateways)
+}
+
+/// Tenants need their own vrf name, vlans and a l3 vni not used by any vlan
+fn check_tenants(cli: &Cli, tenants: &[Tenant], vni_args: &VniArgs, vlans: &[u16]) -> Result<()> {
+    let l2_vnis = vlans
+        .iter()
+        .map(|vlan| vni_args.vni(*vlan))
+        .collect::<Result<Vec<_>>>()?;
+    let mut names = HashMap::new();
+    let mut tenant_vlans = HashMap::new();
+    let mut l3vnis = HashMap::new();
+    for (i, tenant) in (2..).zip(tenants) {
+        let error = |msg: String| line_error(&cli.tenants_filename, i, &tenant.name, msg);
+        if let Some(line) = names.insert(&tenant.name, i) {
+            return Err(error(format!("duplicate name found on line {line}")));
+        }
+        if let Some(line) = l3vnis.insert(tenant.l3vni, i) {
+            return Err(error(format!("duplicate l3vni found on line {line}")));
+        }
+        if tenant.l3vni == 0 || tenant.l3vni > MAX_VNI {
+            return Err(error(format!(
+                "l3vni {} is outside of 1-{MAX_VNI}",
+                tenant.l3vni
+            )));
+        }
+        if l2_vnis.contains(&tenant.l3vni) {
+            return Err(error(format!("l3vni {} is used by a vlan", tenant.l3vni)));
+        }
+        for vlan in &tenant.vlans {
+            if let Some(line) = tenant_vlans.insert(*vlan, i) {
+                return Err(error(format!(
+                    "vlan {vlan} is already in the tenant on line {line}"
+                )));
+            }
+        }
+    }
+    Ok(())
+}
 
-        /// Use EVPN with vxlan
-        #[arg(short, long, default_value_t = 65001)]
-        as_num: u32,
+fn read_ports(cli: &Cli) -> Result<Vec<BridgePort>> {
+    read_optional_csv(&cli.ports_filename)
+}
 
-        /// Anycast gateway vlans
-        #[arg(short, long, value_delimiter = ',')]
-        vlans: Option<Vec<u16>>,
+/// Ports need a known node, vlans carried by that node and settings matching their mode
+fn check_ports(cli: &Cli, ports: &[BridgePort], records: &[Record]) -> Result<()> {
+    let mut interfaces = HashMap::new();
+    for (i, port) in (2..).zip(ports) {
+        let error = |msg: String| {
+            line_error(
+                &cli.ports_filename,
+                i,
+                format!("{} {}", port.node, port.interface),
+                msg,
+            )
+        };
+        let r = records
+            .iter()
+            .find(|r| r.name == port.node)
+            .ok_or_else(|| error("unknown node".to_owned()))?;
+        if let Some(line) = interfaces.insert((&port.node, &port.interface), i) {
+            return Err(error(format!("duplicate port found on line {line}")));
+        }
+        let tagged = port.vlans.as_ref().is_some_and(|vlans| !vlans.is_empty());
+        match port.mode {
+            PortMode::Access if port.pvid.is_none() || tagged => {
+                return Err(error("access ports need a pvid and no vlans".to_owned()));
+            }
+            PortMode::Trunk if port.pvid.is_some() || !tagged => {
+                return Err(error("trunk ports need vlans and no pvid".to_owned()));
+            }
+            PortMode::Hybrid if port.pvid.is_none() || !tagged => {
+                return Err(error("hybrid ports need a pvid and vlans".to_owned()));
+            }
+            _ => {}
+        }
+        // A port can't be both tagged and untagged in the same vlan
+        if let Some(pvid) = port.pvid
+            && port.vlans.iter().flatten().any(|vlan| *vlan == pvid)
+        {
+            return Err(error(format!("pvid {pvid} is also a tagged vlan")));
+        }
+        for vlan in port.pvid.iter().chain(port.vlans.iter().flatten()) {
+            if !carries_vlan(r, *vlan) {
+                return Err(error(format!("vlan {vlan} isn't set on the node")));
+            }
+        }
+    }
+    Ok(())
+}
 
-        /// Anycast gateway addresses
-        #[arg(long, value_delimiter = ',')]
-        anycast_addresses: Option<Vec<IpAddr>>,
+/// Bridge ports of a node
+fn node_ports(r: &Record, ports: &[BridgePort]) -> Result<Vec<BridgePort>> {
+    let node_ports = ports
+        .iter()
+        .filter(|p| p.node == r.name)
+        .cloned()
+        .collect::<Vec<_>>();
+    if !node_ports.is_empty() {
+        return Ok(node_ports);
+    }
+    let ifs = r.vlan_ifs.clone().unwrap_or_default();
+    let vlans = r.vlan.iter().flatten().copied();
+    Ok(ifs
+        .into_iter()
+        .zip(vlans)
+        .map(|(interface, vlan)| BridgePort {
+            node: r.name.clone(),
+            interface,
+            mode: PortMode::Access,
+            pvid: Some(vlan),
+            vlans: None,
+        })
+        .collect())
+}
 
-        /// DNAT support
-        #[arg(short, long, default_value_t = false)]
-        dnat: bool,
-    },
+/// OSPF cost of the link between two nodes, node pairs take precedence over regions
+fn link_cost(costs: &[LinkCost], a: &Record, b: &Record) -> Option<u16> {
+    costs
+        .iter()
+        .find(|c| c.connects(&a.name, &b.name))
+        .or_else(|| match (&a.region, &b.region) {
+            (Some(region_a), Some(region_b)) => {
+                costs.iter().find(|c| c.connects(region_a, region_b))
+            }
+            _ => None,
+        })
+        .and_then(|c| c.value())
+}
 
-    /// Creates DNAT csv file
-    NatInit,
+fn carries_vlan(r: &Record, vlan: u16) -> bool {
+    r.vlan.as_ref().is_some_and(|vlans| vlans.contains(&vlan))
+}
 
-    /// Generate NAT config
-    NatGen,
+/// Nodes carrying each vlan
+fn vlan_members(records: &[Record]) -> BTreeMap<u16, Vec<&Record>> {
+    let mut members: BTreeMap<u16, Vec<&Record>> = BTreeMap::new();
+    for r in records {
+        for vlan in r.vlan.iter().flatten() {
+            members.entry(*vlan).or_default().push(r);
+        }
+    }
+    members
 }
 
-#[serde_as]
-#[derive(Debug, Serialize, Deserialize)]
-struct Record {
-    name: String,
-    interface: String,
-    endpoint: Option<String>,
-    loopback: IpAddr,
-    port_min: Option<u16>,
-    port_max: Option<u16>,
-    keepalive: Option<u64>,
-    privkey: Option<Privkey>,
-    #[serde_as(as = "Option<StringWithSeparator::<SemicolonSeparator, u16>>")]
-    vlan: Option<Vec<u16>>,
-    #[serde_as(as = "Option<StringWithSeparator::<SemicolonSeparator, String>>")]
-    vlan_ifs: Option<Vec<String>>,
-    #[serde_as(as = "Option<StringWithSeparator::<SemicolonSeparator, String>>")]
-    ifs_ips: Option<Vec<String>>,
+fn is_route_reflector(r: &Record) -> bool {
+    r.role == Some(BgpRole::RouteReflector)
 }
 
-#[serde_as]
-#[derive(Debug, Serialize, Deserialize)]
-struct SimpleNat {
-    comment: String,
-    dest_ip: IpAddr,
-    protocol: Option<String>,
-    dest_port: Option<u16>,
-    rewrite_ip: IpAddr,
-    rewrite_port: Option<u16>,
+/// iBGP sessions of a node with the local role of each one, route reflector
+/// clients only peer with the reflectors
+fn bgp_neighbors<'a>(r: &Record, records: &'a [Record]) -> Vec<(&'a Record, &'static str)> {
+    let reflectors = records.iter().any(is_route_reflector);
+    records
+        .iter()
+        .filter(|b| r.name != b.name)
+        .filter_map(
+            |b| match (reflectors, is_route_reflector(r), is_route_reflector(b)) {
+                (false, _, _) | (true, true, true) => Some((b, "ibgp")),
+                (true, true, false) => Some((b, "ibgp-rr")),
+                (true, false, true) => Some((b, "ibgp-rr-client")),
+                (true, false, false) => None,
+            },
+        )
+        .collect()
 }
 
-#[serde_as]
-#[derive(Debug, Serialize, Deserialize)]
-struct CustomNat {
-    comment: String,
-    custom_cmd: String,
+/// First free host address of the pool
+fn next_free_loopback(pool: &str, records: &[Record]) -> Result<IpAddr> {
+    let (ip, prefix) = parse_prefix(pool).context(format!("invalid loopback pool {pool}"))?;
+    let (base, bits) = match network((ip, prefix)) {
+        IpAddr::V4(ip) => (u32::from(ip) as u128, 32),
+        IpAddr::V6(ip) => (u128::from(ip), 128),
+    };
+    let size = 1u128.checked_shl(bits - prefix as u32).unwrap_or(u128::MAX);
+    (1..size)
+        .map(|i| match ip {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::from((base + i) as u32)),
+            IpAddr::V6(_) => IpAddr::V6(std::net::Ipv6Addr::from(base + i)),
+        })
+        .find(|ip| records.iter().all(|r| r.loopback != *ip))
+        .context(format!("no free loopback left in {pool}"))
 }
 
-#[serde_as]
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(untagged)]
-// #[serde(tag = "type")]
-enum NatRecord {
-    Simple(SimpleNat),
-    Custom(CustomNat),
+/// Sets `field=value` pairs through the csv representation of the node
+fn set_fields(record: &Record, fields: &[String]) -> Result<Record> {
+    let mut wtr = csv::Writer::from_writer(vec![]);
+    wtr.serialize(record)?;
+    let data = wtr.into_inner()?;
+    let mut rdr = csv::Reader::from_reader(data.as_slice());
+    let headers = rdr.headers()?.clone();
+    let mut values = rdr
+        .records()
+        .next()
+        .context("serialization error")??
+        .iter()
+        .map(|v| v.to_owned())
+        .collect::<Vec<_>>();
+    for field in fields {
+        let (key, value) = field
+            .split_once('=')
+            .context(format!("{field}: expected field=value"))?;
+        let column = headers
+            .iter()
+            .position(|h| h == key)
+            .context(format!("{key} isn't a node field"))?;
+        values[column] = value.to_owned();
+    }
+    csv::StringRecord::from(values)
+        .deserialize(Some(&headers))
+        .context(format!("{}: invalid value", record.name))
 }
 
-fn export_configs(cli: &Cli, configs: HashMap<String, String>) -> Result<(), anyhow::Error> {
-    match &cli.output_folder {
-        None => {
-            for (node, config) in configs {
-                println!("{node}:\n{config}");
+/// Every check of the nodes file and of the files referencing the nodes
+fn check_records(cli: &Cli, records: &[Record], vni_args: &VniArgs) -> Result<()> {
+    let nodes = records.len() as u16;
+
+    let mut maps: Vec<HashMap<String, usize>> = vec![];
+    for _ in 0..4 {
+        maps.push(HashMap::new());
+    }
+    // Bridge macs only keep the last 4 bytes of the loopback
+    let mut bridge_macs = HashMap::new();
+
+    let mut smallest_port_range = u16::MAX;
+    // Port ranges of nodes sharing the same public endpoint
+    // endpoint -> [(line, name, port_min, port_max)]
+    let mut endpoint_ranges: HashMap<String, Vec<(usize, String, u16, u16)>> = HashMap::new();
+    // Csv lines, or positions in the nodes list
+    let first = match InventoryFormat::from_path(&cli.filename) {
+        InventoryFormat::Csv => 2,
+        _ => 1,
+    };
+    for (i, record) in (first..).zip(records) {
+        // Check for duplicate name, interface, loopback, privkey
+        for ((k, field), field_name) in [
+            record.name.clone(),
+            record.interface.clone(),
+            record.loopback.to_string(),
+            record
+                .privkey
+                .context(format!(
+                    "{}: {} {}: missing privkey",
+                    cli.filename.display(),
+                    i,
+                    record.name,
+                ))?
+                .to_string(),
+        ]
+        .iter()
+        .enumerate()
+        .zip(["name", "interface", "loopback", "privkey"])
+        {
+            if let Some(prev_record) = maps[k].get(field) {
+                Err(anyhow!(format!(
+                    "{}:{} {}: duplicate {} found on line {}",
+                    cli.filename.display(),
+                    prev_record,
+                    record.name,
+                    field_name,
+                    i
+                )))?;
+            } else {
+                maps[k].insert(field.to_owned(), i);
             }
-            Ok(())
         }
-        Some(output_folder) => {
-            let path = Path::new(output_folder);
-            if path.exists() && !path.is_dir() {
-                return Err(anyhow::anyhow!(format!(
-                    "A file named {} already exists",
-                    output_folder.display()
-                )));
+
+        let mac = bridge_mac(record.loopback);
+        if let Some(prev_record) = bridge_macs.insert(mac, i) {
+            Err(anyhow!(format!(
+                "{}:{} {}: bridge mac {} is the same as on line {}",
+                cli.filename.display(),
+                i,
+                record.name,
+                mac,
+                prev_record
+            )))?;
+        }
+
+        // Not enough port Check
+        if let Some(port_min) = record.port_min {
+            // Without port_max the node listens on one port per peer
+            let port_max = match record.port_max {
+                Some(port_max) => port_max,
+                None => port_min
+                    .checked_add(nodes.saturating_sub(2))
+                    .context(format!(
+                        "{}:{} {}: port_min {} is too high for {} peers",
+                        cli.filename.display(),
+                        i,
+                        record.name,
+                        port_min,
+                        nodes.saturating_sub(1)
+                    ))?,
+            };
+            let range = port_max.checked_sub(port_min).context(format!(
+                "{}:{} {}: invalid port range port_min > port_max",
+                cli.filename.display(),
+                i,
+                record.name
+            ))? + 1;
+
+            if record.port_max.is_some() && range < nodes {
+                Err(anyhow!(format!(
+                    "{}:{} {}: needs {} listening ports, but only {} were allowed ({}-{})",
+                    cli.filename.display(),
+                    i,
+                    record.name,
+                    nodes,
+                    range,
+                    port_min,
+                    port_max
+                )))?;
             }
-            if !path.is_dir() {
-                fs::create_dir(output_folder)?;
+
+            smallest_port_range = min(smallest_port_range, range);
+
+            for endpoint in [&record.endpoint, &record.endpoint_v6]
+                .into_iter()
+                .flatten()
+            {
+                let public_min = endpoint.port.unwrap_or(port_min);
+                let public_max = public_min
+                    .checked_add(port_max - port_min)
+                    .context(format!(
+                        "{}:{} {}: endpoint port {} is too high for {} listening ports",
+                        cli.filename.display(),
+                        i,
+                        record.name,
+                        public_min,
+                        range
+                    ))?;
+                endpoint_ranges
+                    .entry(endpoint.host.to_string())
+                    .or_default()
+                    .push((i, record.name.clone(), public_min, public_max));
             }
+        }
 
-            for (node, config) in &configs {
-                let mut filepath = output_folder.clone();
-                filepath.push(format!("{node}.rsc"));
-                let mut file = File::create(filepath)?;
-                file.write_all(config.as_bytes())?;
+        if let Some(underlay_mtu) = record.underlay_mtu
+            && underlay_mtu < MIN_UNDERLAY_MTU
+        {
+            Err(anyhow!(format!(
+                "{}:{} {}: underlay_mtu {} is below the {} minimum",
+                cli.filename.display(),
+                i,
+                record.name,
+                underlay_mtu,
+                MIN_UNDERLAY_MTU
+            )))?;
+        }
+
+        if let Some(endpoint) = &record.endpoint_v6
+            && endpoint.is_v4()
+        {
+            Err(anyhow!(format!(
+                "{}:{} {}: endpoint_v6 {} is an ipv4 address",
+                cli.filename.display(),
+                i,
+                record.name,
+                endpoint
+            )))?;
+        }
+
+        if let Some(ips) = &record.ifs_ips {
+            for ip in ips {
+                if !ip.contains("/") {
+                    return Err(anyhow!(format!(
+                        "{}: {} {}: {ip} doesn't have netmask",
+                        cli.filename.display(),
+                        i,
+                        record.name
+                    )));
+                }
+            }
+        }
+    }
+
+    // Nodes behind the same public ip need disjoint port ranges
+    for (endpoint, ranges) in &endpoint_ranges {
+        for (x, (line_a, name_a, min_a, max_a)) in ranges.iter().enumerate() {
+            for (line_b, name_b, min_b, max_b) in ranges.iter().skip(x + 1) {
+                if min_a <= max_b && min_b <= max_a {
+                    Err(anyhow!(format!(
+                        "{}:{} {}: port range {}-{} overlaps with {} on line {} ({}-{}), both use endpoint {}",
+                        cli.filename.display(),
+                        line_b,
+                        name_b,
+                        min_b,
+                        max_b,
+                        name_a,
+                        line_a,
+                        min_a,
+                        max_a,
+                        endpoint
+                    )))?;
+                }
+            }
+        }
+    }
+
+    let vlan_defs = read_vlan_defs(cli)?;
+    check_vlan_defs(cli, &vlan_defs, records)?;
+    let vni_args = &vni_args
+        .with_settings(&read_settings(cli)?)?
+        .with_vlan_defs(&vlan_defs);
+
+    vni_args.check(
+        vlan_members(records)
+            .into_keys()
+            .chain(vni_args.vni_map.iter().map(|(vlan, _)| *vlan)),
+    )?;
+    check_tenants(
+        cli,
+        &read_tenants(cli)?,
+        vni_args,
+        &vlan_members(records).into_keys().collect::<Vec<_>>(),
+    )?;
+    check_ports(cli, &read_ports(cli)?, records)?;
+
+    // Link costs have to reference known nodes or regions
+    for (i, cost) in (2..).zip(read_link_costs(cli)?) {
+        for end in [&cost.a, &cost.b] {
+            if !records
+                .iter()
+                .any(|r| &r.name == end || r.region.as_ref() == Some(end))
+            {
+                Err(line_error(
+                    &cli.costs_filename,
+                    i,
+                    end,
+                    "isn't a known node or region",
+                ))?;
             }
-            println!("wrote {} configs", configs.len());
-            Ok(())
+        }
+        if cost.value().is_none_or(|c| c == 0) {
+            Err(line_error(
+                &cli.costs_filename,
+                i,
+                format!("{}-{}", cost.a, cost.b),
+                "needs a cost or latency above 0",
+            ))?;
         }
     }
+    Ok(())
 }
 
 fn main() -> Result<()> {
@@ -167,55 +2046,62 @@ fn main() -> Result<()> {
 
     match &cli.command {
         Some(Commands::Init) => {
-            let mut wtr = csv::Writer::from_path(cli.filename.clone())
-                .context(format!("Failed to write csv to {}", cli.filename.display()))?;
-            wtr.serialize(Record {
-                name: "node1".to_owned(),
-                interface: "node1".to_owned(),
-                endpoint: Some("10.200.0.10".to_owned()),
-                loopback: IpAddr::V4(Ipv4Addr::new(10, 69, 0, 10)),
-                port_min: Some(1000),
-                port_max: Some(1050),
-                keepalive: Some(25),
-                privkey: Some(Privkey::generate()),
-                vlan: Some(vec![100, 101]),
-                vlan_ifs: Some(vec!["ether2".to_owned(), "ether3".to_owned()]),
-                ifs_ips: Some(vec![
-                    "192.168.0.5/24".to_owned(),
-                    "192.168.1.5/24".to_owned(),
-                ]),
-            })?;
+            write_records(
+                &cli.filename,
+                &[Record {
+                    name: "node1".to_owned(),
+                    interface: "node1".to_owned(),
+                    endpoint: Some("10.200.0.10".parse()?),
+                    endpoint_v6: None,
+                    loopback: IpAddr::V4(Ipv4Addr::new(10, 69, 0, 10)),
+                    port_min: Some(1000),
+                    port_max: Some(1050),
+                    keepalive: Some(25),
+                    privkey: Some(Privkey::generate()),
+                    lan_address: None,
+                    underlay_mtu: None,
+                    region: None,
+                    role: None,
+                    asn: None,
+                    tags: None,
+                    vlan: Some(vec![100, 101]),
+                    vlan_ifs: Some(vec!["ether2".to_owned(), "ether3".to_owned()]),
+                    ifs_ips: Some(vec![
+                        "192.168.0.5/24".to_owned(),
+                        "192.168.1.5/24".to_owned(),
+                    ]),
+                }],
+            )?;
             println!(
                 "{} was created.",
                 cli.filename.to_str().context("filename error")?
             );
-            wtr.flush()
-                .context(format!("Failed to write to {}", cli.filename.display()))?;
+
+            let settings = Settings {
+                ptp_start_ip: Some(IpAddr::V4(Ipv4Addr::new(10, 70, 0, 0))),
+                ospf: Some(true),
+                evpn: Some(true),
+                as_num: Some(65001),
+                ..Default::default()
+            };
+            fs::write(cli.settings_filename(), toml::to_string(&settings)?).context(format!(
+                "Failed to write settings to {}",
+                cli.settings_filename().display()
+            ))?;
+            println!("{} was created.", cli.settings_filename().display());
         }
         Some(Commands::GenPrivkeys) => {
-            let mut rdr = csv::Reader::from_path(cli.filename.clone()).context(format!(
-                "Failed to read csv from {}",
-                cli.filename.display()
-            ))?;
             let mut generated_privkeys: u32 = 0;
-            let mut records = vec![];
+            let mut records = read_records(&cli.filename)?;
 
-            for result in rdr.deserialize() {
-                let mut record: Record = result?;
+            for record in &mut records {
                 if record.privkey.is_none() {
                     record.privkey = Some(Privkey::generate());
                     generated_privkeys += 1;
                 }
-                records.push(record);
             }
 
-            let mut wtr = csv::Writer::from_path(cli.filename.clone())
-                .context(format!("Failed to write csv to {}", cli.filename.display()))?;
-            records
-                .iter()
-                .try_for_each(|r| wtr.serialize(r).context("csv reading error"))?;
-            wtr.flush()
-                .context(format!("Failed to write to {}", cli.filename.display()))?;
+            write_records(&cli.filename, &records)?;
 
             if generated_privkeys > 0 {
                 println!("{generated_privkeys} key(s) were generated");
@@ -223,120 +2109,263 @@ fn main() -> Result<()> {
                 println!("no keys were generated");
             }
         }
-        Some(Commands::Check) => {
-            // Number of nodes (will be optimized)
-            let nodes = csv::Reader::from_path(cli.filename.clone())?
-                .deserialize::<Record>()
-                .count() as u16;
-
-            let mut rdr = csv::Reader::from_path(cli.filename.clone()).context(format!(
-                "Failed to read csv from {}",
-                cli.filename.display()
-            ))?;
-
-            let mut maps: Vec<HashMap<String, usize>> = vec![];
-            for _ in 0..4 {
-                maps.push(HashMap::new());
-            }
-
-            let mut smallest_port_range = u16::MAX;
-            for (i, result) in (2..).zip(rdr.deserialize()) {
-                let record: Record = result?;
-
-                // Check for duplicate name, interface, loopback, privkey
-                for ((k, field), field_name) in [
-                    record.name.clone(),
-                    record.interface,
-                    record.loopback.to_string(),
-                    record
-                        .privkey
-                        .context(format!(
-                            "{}: {} {}: missing privkey",
-                            cli.filename.display(),
-                            i,
-                            record.name,
-                        ))?
-                        .to_string(),
-                ]
-                .iter()
-                .enumerate()
-                .zip(["name", "interface", "loopback", "privkey"])
-                {
-                    if let Some(prev_record) = maps[k].get(field) {
-                        Err(anyhow!(format!(
-                            "{}:{} {}: duplicate {} found on line {}",
-                            cli.filename.display(),
-                            prev_record,
-                            record.name,
-                            field_name,
-                            i
-                        )))?;
-                    } else {
-                        maps[k].insert(field.to_owned(), i);
+        Some(Commands::Convert { output }) => {
+            let records = read_records(&cli.filename)?;
+            write_records(output, &records)?;
+            println!(
+                "{} nodes were written to {}",
+                records.len(),
+                output.display()
+            );
+        }
+        Some(Commands::Node { command }) => {
+            let mut records = if cli.filename.exists() {
+                read_records(&cli.filename)?
+            } else {
+                vec![]
+            };
+            match command {
+                NodeCommands::Add {
+                    name,
+                    interface,
+                    endpoint,
+                    endpoint_v6,
+                    loopback,
+                    loopback_pool,
+                    port_min,
+                    ports: port_count,
+                    keepalive,
+                } => {
+                    if records.iter().any(|r| r.name == *name) {
+                        return Err(anyhow!("{name} already exists"));
                     }
+                    let loopback = match loopback {
+                        Some(loopback) => *loopback,
+                        None => next_free_loopback(loopback_pool, &records)?,
+                    };
+                    // Every node needs a port per peer, ranges don't overlap to allow shared endpoints
+                    let ports = records
+                        .iter()
+                        .filter_map(|r| Some((r.port_min?, r.port_max?)))
+                        .collect::<Vec<_>>();
+                    let range = (*port_count).max(records.len() as u16 + 1);
+                    let port_min = match port_min {
+                        Some(port_min) => *port_min,
+                        None => match ports.iter().map(|(_, max)| *max).max() {
+                            Some(max) => max
+                                .checked_add(1)
+                                .context(format!("no {range} ports left after {max}"))?,
+                            None => 1000,
+                        },
+                    };
+                    let port_max = port_min
+                        .checked_add(range - 1)
+                        .context(format!("no {range} ports left after {port_min}"))?;
+                    records.push(Record {
+                        name: name.clone(),
+                        interface: interface.clone().unwrap_or(name.clone()),
+                        endpoint: endpoint.clone(),
+                        endpoint_v6: endpoint_v6.clone(),
+                        loopback,
+                        port_min: Some(port_min),
+                        port_max: Some(port_max),
+                        keepalive: Some(*keepalive),
+                        privkey: Some(Privkey::generate()),
+                        lan_address: None,
+                        underlay_mtu: None,
+                        region: None,
+                        role: None,
+                        asn: None,
+                        tags: None,
+                        vlan: None,
+                        vlan_ifs: None,
+                        ifs_ips: None,
+                    });
+                    check_records(&cli, &records, &VniArgs::default())?;
+                    write_records(&cli.filename, &records)?;
+                    println!(
+                        "{name} was added with loopback {loopback} and ports {port_min}-{port_max}"
+                    );
                 }
-
-                // Not enough port Check
-                if let Some(port_min) = record.port_min
-                    && let Some(port_max) = record.port_max
-                {
-                    let range = port_max.checked_sub(port_min).context(format!(
-                        "{}:{} {}: invalid port range port_min > port_max",
-                        cli.filename.display(),
-                        i,
-                        record.name
-                    ))? + 1;
-
-                    if range < nodes {
-                        Err(anyhow!(format!(
-                            "{}:{} {}: needs {} listening ports, but only {} were allowed ({}-{})",
-                            cli.filename.display(),
-                            i,
-                            record.name,
-                            nodes,
-                            range,
-                            port_min,
-                            port_max
-                        )))?;
-                    }
-
-                    smallest_port_range = min(smallest_port_range, range);
-                }
-
-                if let Some(ips) = record.ifs_ips {
-                    for ip in ips {
-                        if !ip.contains("/") {
-                            return Err(anyhow!(format!(
-                                "{}: {} {}: {ip} doesn't have netmask",
-                                cli.filename.display(),
-                                i,
-                                record.name
-                            )));
-                        }
+                NodeCommands::Remove { name } => {
+                    let i = records
+                        .iter()
+                        .position(|r| r.name == *name)
+                        .context(format!("{name} isn't a known node"))?;
+                    records.remove(i);
+                    check_records(&cli, &records, &VniArgs::default())?;
+                    write_records(&cli.filename, &records)?;
+                    println!("{name} was removed");
+                }
+                NodeCommands::Set { name, fields } => {
+                    let i = records
+                        .iter()
+                        .position(|r| r.name == *name)
+                        .context(format!("{name} isn't a known node"))?;
+                    records[i] = set_fields(&records[i], fields)?;
+                    check_records(&cli, &records, &VniArgs::default())?;
+                    write_records(&cli.filename, &records)?;
+                    println!("{name} was updated");
+                }
+            }
+        }
+        Some(Commands::Check { vni: vni_args }) => {
+            let records = read_records(&cli.filename)?;
+            check_records(&cli, &records, vni_args)?;
+            let vlan_defs = read_vlan_defs(&cli)?;
+
+            // Vlan fabric
+            for (vlan, members) in vlan_members(&records) {
+                let missing = records
+                    .iter()
+                    .filter(|r| !carries_vlan(r, vlan))
+                    .map(|r| r.name.as_str())
+                    .collect::<Vec<_>>();
+                let name = vlan_defs
+                    .iter()
+                    .find(|def| def.id == vlan)
+                    .and_then(|def| def.name.as_ref())
+                    .map(|name| format!(" ({name})"))
+                    .unwrap_or_default();
+                println!(
+                    "vlan {vlan}{name}: {}{}",
+                    members
+                        .iter()
+                        .map(|r| r.name.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                    if missing.is_empty() {
+                        String::new()
+                    } else {
+                        format!(" (missing: {})", missing.join(", "))
                     }
+                );
+                if members.len() == 1 {
+                    println!(
+                        "warning: vlan {vlan} only exists on {}, it won't be extended over the mesh",
+                        members[0].name
+                    );
                 }
             }
-            println!("{}: {} nodes are valid", cli.filename.display(), nodes);
+
+            println!(
+                "{}: {} nodes are valid",
+                cli.filename.display(),
+                records.len()
+            );
         }
         Some(Commands::GenConfig {
             ptp_start_ip,
+            profile,
             ospf,
+            no_ospf,
             evpn,
+            no_evpn,
             as_num,
             vlans,
             anycast_addresses,
             dnat,
+            no_dnat,
+            dnat_return,
+            l2_mtu,
+            bfd,
+            no_bfd,
+            bfd_interval,
+            bfd_multiplier,
+            cluster_id,
+            underlay,
+            firewall,
+            no_firewall,
+            vni: vni_args,
         }) => {
+            // Flags override the settings file
+            let settings = read_settings(&cli)?;
+            let ptp_start_ip = &ptp_start_ip.or(settings.ptp_start_ip).context(format!(
+                "ptp_start_ip has to be set in {} or with --ptp-start-ip",
+                cli.settings_filename().display()
+            ))?;
+            let profile = profile.or(settings.profile).unwrap_or(Profile::L3Evpn);
+            let ospf = &switch(*ospf, *no_ospf).or(settings.ospf).unwrap_or(true);
+            let evpn = &switch(*evpn, *no_evpn)
+                .or(settings.evpn)
+                .unwrap_or(profile != Profile::UnderlayOnly);
+            let as_num = &as_num.or(settings.as_num).unwrap_or(65001);
+            let vlans = &vlans.clone().or(settings.vlans.clone());
+            let anycast_addresses = &anycast_addresses
+                .clone()
+                .or(settings.anycast_addresses.clone());
+            let dnat = &switch(*dnat, *no_dnat).or(settings.dnat).unwrap_or(false);
+            let dnat_return = &dnat_return
+                .or(settings.dnat_return)
+                .unwrap_or(DnatReturn::Mac);
+            let l2_mtu = &l2_mtu.or(settings.l2_mtu);
+            let bfd = &switch(*bfd, *no_bfd).or(settings.bfd).unwrap_or(false);
+            let bfd_interval = &bfd_interval.or(settings.bfd_interval).unwrap_or(200);
+            let bfd_multiplier = &bfd_multiplier.or(settings.bfd_multiplier).unwrap_or(5);
+            let cluster_id = &cluster_id.or(settings.cluster_id);
+            let underlay = &underlay.or(settings.underlay).unwrap_or(Underlay::Ospf);
+            let firewall = &switch(*firewall, *no_firewall)
+                .or(settings.firewall)
+                .unwrap_or(false);
+            // Vlan addresses, gateways, dhcp and tenants are part of the l3 evpn
+            let l3 = *evpn && profile == Profile::L3Evpn;
+
+            if *evpn && !*ospf && *underlay == Underlay::Ospf {
+                return Err(anyhow!(
+                    "EVPN needs routed loopbacks, enable OSPF or use the ebgp underlay"
+                ));
+            }
+
             // Generate PTP ip pairs
 
             let mut configs = HashMap::new();
 
-            let mut rdr = csv::Reader::from_path(cli.filename.clone()).context(format!(
-                "Failed to read csv from {}",
-                cli.filename.display()
-            ))?;
+            let records = read_records(&cli.filename)?;
+            // The tunnel and vxlan mtus are the underlay mtu minus their overheads
+            if let Some(r) = records
+                .iter()
+                .find(|r| r.underlay_mtu.is_some_and(|mtu| mtu < MIN_UNDERLAY_MTU))
+            {
+                return Err(anyhow!(
+                    "{}: underlay_mtu {} is below the {MIN_UNDERLAY_MTU} minimum",
+                    r.name,
+                    r.underlay_mtu.unwrap()
+                ));
+            }
+
+            let vlan_defs = read_vlan_defs(&cli)?;
+            check_vlan_defs(&cli, &vlan_defs, &records)?;
+            let vni_args = &vni_args
+                .with_settings(&settings)?
+                .with_vlan_defs(&vlan_defs);
+            let mut anycast = anycast_gateways(&vlan_defs, vlans, anycast_addresses)?;
+            if !l3 && !anycast.is_empty() {
+                println!(
+                    "warning: anycast gateways are only generated with evpn and the l3-evpn profile"
+                );
+                anycast.clear();
+            }
 
-            let records: Vec<Record> = rdr.deserialize().collect::<Result<Vec<_>, _>>()?;
+            if *underlay == Underlay::Ebgp {
+                for r in &records {
+                    if r.asn.is_none() {
+                        return Err(anyhow!(
+                            "{}: the ebgp underlay needs an asn on every node",
+                            r.name
+                        ));
+                    }
+                    if r.role.is_some() {
+                        return Err(anyhow!(
+                            "{}: route reflectors can't be used with the ebgp underlay",
+                            r.name
+                        ));
+                    }
+                }
+            }
+            let asn = |r: &Record| match underlay {
+                Underlay::Ospf => *as_num,
+                Underlay::Ebgp => r.asn.unwrap(),
+            };
 
             // Create config entries
             for r in &records {
@@ -358,25 +2387,21 @@ fn main() -> Result<()> {
                     .push_str("\n\n/interface wireguard\nremove [find comment=\"mt-wg-meshconf\"]")
             });
 
-            // bad way to store which enpoint port each peer has to use
-            // ((local_peer, remote_peer), port)
-            let mut port_assignations = HashMap::new();
+            let port_assignations = assign_ports(&records)?;
 
             // "server side" config
             for server in &records {
-                let mut port = server.port_min.context("no min port set")?;
                 for peer in &records {
                     if server.name == peer.name {
                         continue;
                     }
                     configs.get_mut(&server.name).unwrap().push_str(&format!(
-                        "\nadd listen-port={} mtu=1420 name={} private-key=\"{}\" comment=mt-wg-meshconf",
-                        port,
+                        "\nadd listen-port={} mtu={} name={} private-key=\"{}\" comment=mt-wg-meshconf",
+                        port_assignations.get(&(server.name.clone(), peer.name.clone())).unwrap(),
+                        wg_mtu(server, peer),
                         peer.interface,
                         server.privkey.context("missing privkey")?
                     ));
-                    port_assignations.insert((server.name.clone(), peer.name.clone()), port);
-                    port += 1;
                 }
             }
 
@@ -393,10 +2418,21 @@ fn main() -> Result<()> {
                     if server.name == peer.name {
                         continue;
                     }
+                    let endpoint = peer_endpoint(server, peer)
+                        .context(format!("{}: no endpoint address", peer.name))?;
+                    let listen_port = port_assignations
+                        .get(&(peer.name.clone(), server.name.clone()))
+                        .unwrap();
+                    let public_port = endpoint
+                        .public_port(*listen_port, peer.port_min.context("no min port set")?)
+                        .context(format!(
+                            "{}: endpoint port of {endpoint} is too high for its listening ports",
+                            peer.name
+                        ))?;
                     configs.get_mut(&server.name).unwrap().push_str(&format!(
                         "\nadd allowed-address=0.0.0.0/0 endpoint-address={} endpoint-port={} interface={} name={} persistent-keepalive={}s public-key=\"{}\" comment=mt-wg-meshconf",
-                        peer.endpoint.clone().context("no endpoint address")?,
-                        port_assignations.get(&(peer.name.clone(), server.name.clone())).unwrap(),
+                        endpoint.host,
+                        public_port,
                         peer.interface,
                         peer.name,
                         peer.keepalive.unwrap_or(0),
@@ -415,14 +2451,14 @@ fn main() -> Result<()> {
             });
 
             // Add PTP addresses
+            // ((local_peer, remote_peer), (local_ip, remote_ip))
+            let mut ptp_pairs = HashMap::new();
             let mut interfaces = vec![];
             let mut ptp_addresses = vec![];
             for (i, a) in records.iter().enumerate() {
-                let mut x = 1;
-                for b in records.iter().skip(x + i) {
-                    interfaces.append(&mut vec![a, b]);
-                    x += 1;
+                for b in records.iter().skip(i + 1) {
                     // Generate addresses pairs
+                    interfaces.append(&mut vec![a, b]);
                 }
             }
 
@@ -453,10 +2489,41 @@ fn main() -> Result<()> {
                     "\nadd address={}/31 interface={} comment=mt-wg-meshconf",
                     ip[1], r[0].interface
                 ));
+
+                ptp_pairs.insert((r[0].name.clone(), r[1].name.clone()), (ip[0], ip[1]));
+                ptp_pairs.insert((r[1].name.clone(), r[0].name.clone()), (ip[1], ip[0]));
+            }
+
+            let use_bfd = if *bfd { " use-bfd=yes" } else { "" };
+
+            // BFD
+            if *bfd {
+                records.iter().for_each(|r| {
+                    let if_list = records
+                        .iter()
+                        .filter(|b| r.name != b.name)
+                        .map(|b| b.interface.as_str())
+                        .collect::<Vec<_>>()
+                        .join(",");
+                    configs.get_mut(&r.name).unwrap().push_str(&format!(
+                        "\n\n/routing bfd configuration\nremove [find comment=\"mt-wg-meshconf\"]\nadd disabled=no interfaces={if_list} min-rx={bfd_interval}ms min-tx={bfd_interval}ms multiplier={bfd_multiplier} comment=mt-wg-meshconf"
+                    ));
+                    // Multihop sessions between loopbacks
+                    if *evpn {
+                        let loopbacks = bgp_neighbors(r, &records)
+                            .iter()
+                            .map(|(b, _)| b.loopback.to_string())
+                            .collect::<Vec<_>>()
+                            .join(",");
+                        configs.get_mut(&r.name).unwrap().push_str(&format!(
+                            "\nadd addresses={loopbacks} disabled=no min-rx={bfd_interval}ms min-tx={bfd_interval}ms multiplier={bfd_multiplier} comment=mt-wg-meshconf"
+                        ));
+                    }
+                });
             }
 
             // OSPF
-            if *ospf {
+            if *ospf && *underlay == Underlay::Ospf {
                 records.iter().for_each(|r| {
                     configs.get_mut(&r.name).unwrap().push_str(
                         &format!("\n\n/routing ospf instance\nremove [find comment=\"mt-wg-meshconf\"]\nadd disabled=no name=ospf-ipv4 router-id={} comment=mt-wg-meshconf", r.loopback),
@@ -472,53 +2539,161 @@ fn main() -> Result<()> {
                         "\n/routing ospf interface-template\nremove [find comment=\"mt-wg-meshconf\"]\nadd area=area0-ipv4 disabled=no interfaces=lo passive comment=mt-wg-meshconf",
                     )
                 });
-                // add individual ospf adjutancies
+                // add individual ospf adjutancies, one template per link cost
 
+                let costs = read_link_costs(&cli)?;
                 records.iter().for_each(|r| {
-                    let mut if_list = String::new();
+                    let mut if_lists: BTreeMap<Option<u16>, Vec<&str>> = BTreeMap::new();
                     for b in &records {
                         if r.name == b.name {
                             continue;
                         }
-                        if_list.push_str(&format!("{},", b.interface));
+                        if_lists
+                            .entry(link_cost(&costs, r, b))
+                            .or_default()
+                            .push(&b.interface);
+                    }
+                    for (cost, if_list) in if_lists {
+                        let cost = cost.map(|c| format!(" cost={c}")).unwrap_or_default();
+                        configs.get_mut(&r.name).unwrap().push_str(
+                            &format!("\nadd area=area0-ipv4{cost} disabled=no interfaces={} type=ptp{use_bfd} comment=mt-wg-meshconf", if_list.join(",")),
+                        )
                     }
-                    if_list.pop();
+                });
+            }
+
+            // BGP
+            if *evpn || *underlay == Underlay::Ebgp {
+                let cluster_id = match cluster_id {
+                    Some(cluster_id) => Some(IpAddr::V4(*cluster_id)),
+                    None => records
+                        .iter()
+                        .find(|r| is_route_reflector(r))
+                        .map(|r| r.loopback),
+                };
+                if cluster_id.is_some_and(|c| c.is_ipv6()) {
+                    return Err(anyhow!(
+                        "cluster id needs to be an ipv4 address, set one with --cluster-id"
+                    ));
+                }
+                records.iter().for_each(|r| {
+                    let cluster_id = match cluster_id {
+                        Some(cluster_id) if is_route_reflector(r) => format!(" cluster-id={cluster_id}"),
+                        _ => String::new(),
+                    };
+                    configs.get_mut(&r.name).unwrap().push_str(
+                        &format!("\n\n/routing bgp instance\nremove [find comment=\"mt-wg-meshconf\"]\nadd as={}{cluster_id} disabled=no name=wg-mesh-bgp router-id={} comment=mt-wg-meshconf", asn(r), r.loopback),
+                    )
+                });
+
+                // Loopback advertised to the eBGP underlay
+                if *underlay == Underlay::Ebgp {
+                    records.iter().for_each(|r| {
+                        configs.get_mut(&r.name).unwrap().push_str(&format!(
+                            "\n/ip firewall address-list\nremove [find comment=\"mt-wg-meshconf\"]\nadd address={} list=wg-mesh-bgp-networks comment=mt-wg-meshconf",
+                            r.loopback
+                        ))
+                    });
+                }
+
+                records.iter().for_each(|r| {
                     configs.get_mut(&r.name).unwrap().push_str(
-                        &format!("\nadd area=area0-ipv4 disabled=no interfaces={if_list} type=ptp comment=mt-wg-meshconf"),
+                        "\n/routing bgp connection\nremove [find comment=\"mt-wg-meshconf\"]",
                     )
                 });
+
+                // eBGP underlay sessions over the ptp links
+                if *underlay == Underlay::Ebgp {
+                    records.iter().for_each(|r| {
+                        for b in &records {
+                            if r.name == b.name {
+                                continue;
+                            }
+                            let (local_ip, remote_ip) =
+                                ptp_pairs.get(&(r.name.clone(), b.name.clone())).unwrap();
+                            let afi = if local_ip.is_ipv4() { "ip" } else { "ipv6" };
+                            configs.get_mut(&r.name).unwrap().push_str(&format!("\nadd afi={afi} connect=yes disabled=no instance=wg-mesh-bgp listen=yes local.address={local_ip} .role=ebgp name=underlay-{} output.network=wg-mesh-bgp-networks remote.address={remote_ip} .as={}{use_bfd} comment=mt-wg-meshconf",
+                                b.interface, asn(b),
+                            ));
+                        }
+                    });
+                }
+
+                // EVPN overlay sessions between loopbacks
+                if *evpn {
+                    records.iter().try_for_each(|r| {
+                        for (b, role) in bgp_neighbors(r, &records) {
+                        // Keep the remote vtep as next hop across eBGP
+                        let (role, multihop) = match underlay {
+                            Underlay::Ospf => (role, ""),
+                            Underlay::Ebgp => ("ebgp", " multihop=yes nexthop-choice=propagate"),
+                        };
+                        configs.get_mut(&r.name).unwrap().push_str(&format!("\nadd afi=evpn connect=yes disabled=no instance=wg-mesh-bgp listen=yes local.address={} .role={role}{multihop} name={} remote.address={}/32 .as={}{use_bfd} comment=mt-wg-meshconf",
+                            r.loopback, b.interface, b.loopback, asn(b),
+                        ));
+                        }
+                        Ok::<(), anyhow::Error>(())
+                    }).context("vxlan error")?;
+                }
             }
 
             let mut bridge_macs = HashMap::new();
 
             // EVPN
             if *evpn {
+                vni_args.check(vlan_members(&records).into_keys())?;
+                for (vlan, members) in vlan_members(&records) {
+                    if members.len() == 1 {
+                        println!(
+                            "warning: vlan {vlan} only exists on {}, it won't be extended over the mesh",
+                            members[0].name
+                        );
+                    }
+                }
+
+                // MTU, the same on the whole l2 overlay
+                let max_mtu = vxlan_mtu(&records);
+                if let Some(l2_mtu) = l2_mtu
+                    && *l2_mtu > max_mtu
+                {
+                    println!(
+                        "warning: l2 mtu {l2_mtu} is larger than the {max_mtu} the tunnels can carry, vxlan packets will be fragmented"
+                    );
+                }
+                let overlay_mtu = l2_mtu.unwrap_or(max_mtu);
+                let dont_fragment = if overlay_mtu > max_mtu {
+                    "disabled"
+                } else {
+                    "enabled"
+                };
+
                 // Bridge
 
-                let mut rng = rand::rng();
                 records.iter().for_each(|r| {
-                    let mut data = [0u8; 6];
-                    rng.fill_bytes(&mut data);
-                    data[0] |= 0x02; // Locally administerred
-                    data[0] &= 0xFE; // Unicast
-                    let mac = macaddr::MacAddr6::from(data);
+                    let mac = bridge_mac(r.loopback);
                     bridge_macs.insert(r.name.clone(), mac);
 
                     configs
                         .get_mut(&r.name)
                         .unwrap()
-                        .push_str(&format!("\n\n/interface bridge\nremove [find comment=\"mt-wg-meshconf\"]\nadd name=wg-mesh-br admin-mac={mac} auto-mac=no vlan-filtering=yes comment=mt-wg-meshconf"))
+                        .push_str(&format!("\n\n/interface bridge\nremove [find comment=\"mt-wg-meshconf\"]\nadd name=wg-mesh-br admin-mac={mac} auto-mac=no mtu={overlay_mtu} vlan-filtering=yes comment=mt-wg-meshconf"))
                 });
                 records.iter().for_each(|r| {
                     configs.get_mut(&r.name).unwrap().push_str(
                         "\n/interface bridge port\nremove [find comment=\"mt-wg-meshconf\"]",
                     )
                 });
+                let ports = read_ports(&cli)?;
+                check_ports(&cli, &ports, &records)?;
                 records.iter().try_for_each(|r| {
-                    let ifs = r.vlan_ifs.clone().context("no vlan if set")?;
-                    let vlans = r.vlan.clone().context("no vlan set")?;
-                    for (i, vlan) in ifs.iter().zip(vlans) {
-                    configs.get_mut(&r.name).unwrap().push_str(&format!("\nadd bridge=wg-mesh-br frame-types=admit-only-untagged-and-priority-tagged interface={i} pvid={vlan} comment=mt-wg-meshconf"));
+                    for port in node_ports(r, &ports)? {
+                        let (frame_types, pvid) = match port.mode {
+                            PortMode::Access => ("admit-only-untagged-and-priority-tagged", port.pvid),
+                            PortMode::Trunk => ("admit-only-vlan-tagged", None),
+                            PortMode::Hybrid => ("admit-all", port.pvid),
+                        };
+                        let pvid = pvid.map(|pvid| format!(" pvid={pvid}")).unwrap_or_default();
+                        configs.get_mut(&r.name).unwrap().push_str(&format!("\nadd bridge=wg-mesh-br frame-types={frame_types} interface={}{pvid} comment=mt-wg-meshconf", port.interface));
                     }
                     Ok::<(), anyhow::Error>(())
                 }).context("vlan error")?;
@@ -531,34 +2706,40 @@ fn main() -> Result<()> {
                         .push_str("\n\n/interface vxlan\nremove [find comment=\"mt-wg-meshconf\"]")
                 });
                 records.iter().try_for_each(|r| {
-                    for vlan in r.vlan.clone().context("no vlan set")? {
-                        configs.get_mut(&r.name).unwrap().push_str(&format!("\nadd bridge=wg-mesh-br bridge-pvid={} dont-fragment=disabled learning=no local-address={} name=vxlan1000{} vni=1000{} comment=mt-wg-meshconf", vlan, r.loopback, vlan, vlan));
+                    for vlan in r.vlan.iter().flatten().copied() {
+                        let vni = vni_args.vni(vlan)?;
+                        configs.get_mut(&r.name).unwrap().push_str(&format!("\nadd bridge=wg-mesh-br bridge-pvid={} dont-fragment={dont_fragment} learning=no local-address={} mtu={overlay_mtu} name=vxlan{vni} vni={vni} comment=mt-wg-meshconf", vlan, r.loopback));
                     }
                     Ok::<(), anyhow::Error>(())
                 }).context("vxlan error")?;
 
-                // BGP
-                records.iter().for_each(|r| {
-                    configs.get_mut(&r.name).unwrap().push_str(
-                        &format!("\n\n/routing bgp instance\nremove [find comment=\"mt-wg-meshconf\"]\nadd as={as_num} disabled=no name=wg-mesh-bgp router-id={} comment=mt-wg-meshconf", r.loopback),
-                    )
-                });
+                // Bridge vlan table
                 records.iter().for_each(|r| {
                     configs.get_mut(&r.name).unwrap().push_str(
-                        "\n/routing bgp connection\nremove [find comment=\"mt-wg-meshconf\"]",
+                        "\n/interface bridge vlan\nremove [find comment=\"mt-wg-meshconf\"]",
                     )
                 });
                 records.iter().try_for_each(|r| {
-                    for b in &records {
-                        if r.name == b.name {
-                            continue;
-                        }
-                    configs.get_mut(&r.name).unwrap().push_str(&format!("\nadd afi=evpn connect=yes disabled=no instance=wg-mesh-bgp listen=yes local.address={} .role=ibgp name={} remote.address={}/32 .as={} comment=mt-wg-meshconf",
-                        r.loopback, b.interface, b.loopback, as_num,
-                    ));
+                    let node_ports = node_ports(r, &ports)?;
+                    for vlan in r.vlan.iter().flatten().copied() {
+                        let members = |f: fn(&BridgePort, u16) -> bool| {
+                            node_ports
+                                .iter()
+                                .filter(|p| f(p, vlan))
+                                .map(|p| p.interface.as_str())
+                                .collect::<Vec<_>>()
+                                .join(",")
+                        };
+                        // The bridge carries the vlan interfaces, vxlans are untagged through bridge-pvid
+                        let tagged = ["wg-mesh-br".to_owned(), members(BridgePort::tagged)];
+                        let untagged = [members(BridgePort::untagged), format!("vxlan{}", vni_args.vni(vlan)?)];
+                        let [tagged, untagged] = [tagged, untagged].map(|m| {
+                            m.into_iter().filter(|m| !m.is_empty()).collect::<Vec<_>>().join(",")
+                        });
+                        configs.get_mut(&r.name).unwrap().push_str(&format!("\nadd bridge=wg-mesh-br vlan-ids={vlan} tagged={tagged} untagged={untagged} comment=mt-wg-meshconf"));
                     }
                     Ok::<(), anyhow::Error>(())
-                }).context("vxlan error")?;
+                }).context("bridge vlan error")?;
 
                 // EVPN
                 records.iter().for_each(|r| {
@@ -568,59 +2749,54 @@ fn main() -> Result<()> {
                         .push_str("\n\n/routing bgp evpn\nremove [find comment=\"mt-wg-meshconf\"]")
                 });
                 records.iter().try_for_each(|r| {
-                    for vlan in r.vlan.clone().context("no vlan set")? {
-                    configs.get_mut(&r.name).unwrap().push_str(&format!("\nadd export.route-targets={as_num}:1000{vlan} import.route-targets={as_num}:1000{vlan} instance=wg-mesh-bgp name=wg-mesh-evpn-1000{vlan} vni=1000{vlan} comment=mt-wg-meshconf"));
+                    for vlan in r.vlan.iter().flatten().copied() {
+                    let vni = vni_args.vni(vlan)?;
+                    let route_targets = vni_args.route_targets(*as_num, vni)?;
+                    configs.get_mut(&r.name).unwrap().push_str(&format!("\nadd{route_targets} instance=wg-mesh-bgp name=wg-mesh-evpn-{vni} vni={vni} comment=mt-wg-meshconf"));
                     }
                     Ok::<(), anyhow::Error>(())
                 }).context("vxlan error")?;
             }
 
             // Vlans IP
-            records.iter().for_each(|r| {
-                configs
-                    .get_mut(&r.name)
-                    .unwrap()
-                    .push_str("\n\n/interface vlan\nremove [find comment=\"mt-wg-meshconf\"]")
-            });
+            if l3 {
+                records.iter().for_each(|r| {
+                    configs
+                        .get_mut(&r.name)
+                        .unwrap()
+                        .push_str("\n\n/interface vlan\nremove [find comment=\"mt-wg-meshconf\"]")
+                });
 
-            records.iter().try_for_each(|r| {
-                for vlan in r.vlan.clone().context("no vlan set")? {
-                    configs.get_mut(&r.name).unwrap().push_str(&format!("\nadd interface=wg-mesh-br name=vlan{vlan} vlan-id={vlan} comment=mt-wg-meshconf"));
-                }
-                Ok::<(), anyhow::Error>(())
-            }).context("vlan interface error")?;
+                records.iter().for_each(|r| {
+                    for vlan in r.vlan.iter().flatten() {
+                        configs.get_mut(&r.name).unwrap().push_str(&format!("\nadd interface=wg-mesh-br name=vlan{vlan} vlan-id={vlan} comment=mt-wg-meshconf"));
+                    }
+                });
 
-            records
-                .iter()
-                .for_each(|r| configs.get_mut(&r.name).unwrap().push_str("\n/ip address"));
+                records
+                    .iter()
+                    .for_each(|r| configs.get_mut(&r.name).unwrap().push_str("\n/ip address"));
 
-            records
-                .iter()
-                .try_for_each(|r| {
-                    if let Some(ifs_ips) = &r.ifs_ips {
-                        for (ip, vlan) in ifs_ips.iter().zip(r.vlan.clone().context("no vlan set")?)
-                        {
-                            configs.get_mut(&r.name).unwrap().push_str(&format!(
-                                "\nadd address={ip} interface=vlan{vlan} comment=mt-wg-meshconf"
-                            ));
-                        }
+                records.iter().for_each(|r| {
+                    for (ip, vlan) in r.ifs_ips.iter().flatten().zip(r.vlan.iter().flatten()) {
+                        configs.get_mut(&r.name).unwrap().push_str(&format!(
+                            "\nadd address={ip} interface=vlan{vlan} comment=mt-wg-meshconf"
+                        ));
                     }
-                    Ok::<(), anyhow::Error>(())
-                })
-                .context("vlan address error")?;
+                });
+            }
 
             // Anycast gateways
 
-            if let Some(vlans) = vlans
-                && let Some(addrs) = anycast_addresses
-            {
-                if vlans.len() != addrs.len() {
-                    return Err(anyhow!(
-                        "Numbers of vlans and anycast addresses don't match"
-                    ));
+            if !anycast.is_empty() {
+                for gateway in &anycast {
+                    if !records.iter().any(|r| carries_vlan(r, gateway.vlan)) {
+                        println!(
+                            "warning: anycast vlan {} isn't carried by any node",
+                            gateway.vlan
+                        );
+                    }
                 }
-                // One anycast mac address for each vlan
-                let mut rng = rand::rng();
 
                 // Create macvlans
                 records.iter().for_each(|r| {
@@ -629,13 +2805,8 @@ fn main() -> Result<()> {
                     )
                 });
 
-                for vlan in vlans {
-                    let mut data = [0u8; 6];
-                    rng.fill_bytes(&mut data);
-                    data[0] |= 0x02; // Locally administerred
-                    data[0] &= 0xFE; // Unicast
-                    let mac = macaddr::MacAddr6::from(data);
-                    records.iter().for_each(|r| {
+                for AnycastGateway { vlan, mac, .. } in &anycast {
+                    records.iter().filter(|r| carries_vlan(r, *vlan)).for_each(|r| {
                         configs.get_mut(&r.name).unwrap().push_str(&format!(
                             "\nadd interface=vlan{vlan} mac-address={mac} name=macvlan-wg-{vlan} comment=mt-wg-meshconf"),
                         )
@@ -648,7 +2819,15 @@ fn main() -> Result<()> {
                     .for_each(|r| configs.get_mut(&r.name).unwrap().push_str("\n/ip address"));
 
                 records.iter().for_each(|r| {
-                    for (vlan, addr) in vlans.iter().zip(addrs) {
+                    for AnycastGateway {
+                        vlan,
+                        address: addr,
+                        ..
+                    } in &anycast
+                    {
+                        if !carries_vlan(r, *vlan) {
+                            continue;
+                        }
                         configs.get_mut(&r.name).unwrap().push_str(&format!(
                             "\nadd interface=macvlan-wg-{vlan} address={addr} comment=mt-wg-meshconf"),
                         )
@@ -656,17 +2835,288 @@ fn main() -> Result<()> {
                 });
             }
 
-            if *dnat {
-                if !evpn {
-                    return Err(anyhow!("EVPN needs to be enable for dnat to work"));
+            // DHCP servers
+            // No node to serve a vlan nobody carries
+            let dhcp_vlans = vlan_defs
+                .iter()
+                .filter(|def| def.dhcp == Some(true))
+                .filter(|def| {
+                    let carried = records.iter().any(|r| carries_vlan(r, def.id));
+                    if !carried {
+                        println!(
+                            "warning: vlan {} isn't carried by any node, no dhcp server is generated",
+                            def.id
+                        );
+                    }
+                    carried
+                })
+                .collect::<Vec<_>>();
+            if !l3 && !dhcp_vlans.is_empty() {
+                println!(
+                    "warning: dhcp servers are only generated with evpn and the l3-evpn profile"
+                );
+            }
+            if l3 && !dhcp_vlans.is_empty() {
+                records.iter().for_each(|r| {
+                    configs
+                        .get_mut(&r.name)
+                        .unwrap()
+                        .push_str("\n\n/ip pool\nremove [find comment=\"mt-wg-meshconf\"]")
+                });
+                for def in &dhcp_vlans {
+                    let vlan = def.id;
+                    let subnet = parse_prefix(def.subnet.as_ref().context("no subnet set")?)?;
+                    let gateway = anycast
+                        .iter()
+                        .find(|g| g.vlan == vlan)
+                        .context(format!("vlan {vlan}: dhcp needs an anycast gateway"))?;
+                    let members = vlan_members(&records).remove(&vlan).unwrap_or_default();
+                    // Gateway and node addresses can't be leased
+                    let mut reserved = vec![
+                        gateway
+                            .address
+                            .split('/')
+                            .next()
+                            .unwrap_or_default()
+                            .parse()?,
+                    ];
+                    for r in &members {
+                        for (ip, v) in r.ifs_ips.iter().flatten().zip(r.vlan.iter().flatten()) {
+                            if *v == vlan {
+                                reserved.push(parse_prefix(ip)?.0);
+                            }
+                        }
+                    }
+                    let ranges = dhcp_ranges(subnet, members.len(), &reserved)
+                        .context(format!("vlan {vlan}"))?;
+                    for (r, ranges) in members.iter().zip(ranges) {
+                        let ranges = ranges
+                            .iter()
+                            .map(|(start, end)| format!("{start}-{end}"))
+                            .collect::<Vec<_>>()
+                            .join(",");
+                        configs.get_mut(&r.name).unwrap().push_str(&format!(
+                            "\nadd name=wg-mesh-{vlan} ranges={ranges} comment=mt-wg-meshconf"
+                        ));
+                    }
+                }
+
+                records.iter().for_each(|r| {
+                    configs
+                        .get_mut(&r.name)
+                        .unwrap()
+                        .push_str("\n/ip dhcp-server\nremove [find comment=\"mt-wg-meshconf\"]")
+                });
+                for def in &dhcp_vlans {
+                    let vlan = def.id;
+                    let lease_time = def
+                        .lease_time
+                        .as_ref()
+                        .map(|l| format!(" lease-time={l}"))
+                        .unwrap_or_default();
+                    records.iter().filter(|r| carries_vlan(r, vlan)).for_each(|r| {
+                        configs.get_mut(&r.name).unwrap().push_str(&format!(
+                            "\nadd address-pool=wg-mesh-{vlan} interface=vlan{vlan}{lease_time} name=wg-mesh-{vlan} comment=mt-wg-meshconf"
+                        ));
+                    });
+                }
+
+                records.iter().for_each(|r| {
+                    configs.get_mut(&r.name).unwrap().push_str(
+                        "\n/ip dhcp-server network\nremove [find comment=\"mt-wg-meshconf\"]",
+                    )
+                });
+                for def in &dhcp_vlans {
+                    let vlan = def.id;
+                    let (network, prefix) =
+                        parse_prefix(def.subnet.as_ref().context("no subnet set")?)?;
+                    let gateway = anycast.iter().find(|g| g.vlan == vlan).unwrap();
+                    let gateway = gateway.address.split('/').next().unwrap_or_default();
+                    let dns = def
+                        .dns_servers
+                        .as_ref()
+                        .map(|dns| {
+                            format!(
+                                " dns-server={}",
+                                dns.iter()
+                                    .map(|ip| ip.to_string())
+                                    .collect::<Vec<_>>()
+                                    .join(",")
+                            )
+                        })
+                        .unwrap_or_default();
+                    records.iter().filter(|r| carries_vlan(r, vlan)).for_each(|r| {
+                        configs.get_mut(&r.name).unwrap().push_str(&format!(
+                            "\nadd address={network}/{prefix}{dns} gateway={gateway} comment=mt-wg-meshconf"
+                        ));
+                    });
                 }
+            }
+
+            // Tenants VRFs
+            let tenants = read_tenants(&cli)?;
+            if !l3 && !tenants.is_empty() {
+                println!("warning: tenants are only generated with evpn and the l3-evpn profile");
+            }
+            if l3 && !tenants.is_empty() {
+                check_tenants(
+                    &cli,
+                    &tenants,
+                    vni_args,
+                    &vlan_members(&records).into_keys().collect::<Vec<_>>(),
+                )?;
+
+                let anycast_vlans = anycast.iter().map(|g| g.vlan).collect::<Vec<_>>();
+                // Tenants present on each node
+                let node_tenants = |r: &Record| {
+                    tenants
+                        .iter()
+                        .filter(|t| t.vlans.iter().any(|vlan| carries_vlan(r, *vlan)))
+                        .collect::<Vec<_>>()
+                };
+
+                records.iter().for_each(|r| {
+                    configs.get_mut(&r.name).unwrap().push_str("\n\n/interface vxlan");
+                    for tenant in node_tenants(r) {
+                        configs.get_mut(&r.name).unwrap().push_str(&format!(
+                            "\nadd learning=no local-address={} name=vxlan{} vni={} comment=mt-wg-meshconf",
+                            r.loopback, tenant.l3vni, tenant.l3vni
+                        ));
+                    }
+                });
 
                 records.iter().for_each(|r| {
                     configs
                         .get_mut(&r.name)
                         .unwrap()
-                        .push_str("\n\n/interface bridge settings set use-ip-firewall=yes")
+                        .push_str("\n/ip vrf\nremove [find comment=\"mt-wg-meshconf\"]");
+                    for tenant in node_tenants(r) {
+                        let mut interfaces = vec![format!("vxlan{}", tenant.l3vni)];
+                        for vlan in tenant.vlans.iter().filter(|vlan| carries_vlan(r, **vlan)) {
+                            interfaces.push(format!("vlan{vlan}"));
+                            if anycast_vlans.contains(vlan) {
+                                interfaces.push(format!("macvlan-wg-{vlan}"));
+                            }
+                        }
+                        configs.get_mut(&r.name).unwrap().push_str(&format!(
+                            "\nadd interfaces={} name={} comment=mt-wg-meshconf",
+                            interfaces.join(","),
+                            tenant.name
+                        ));
+                    }
+                });
+
+                records.iter().try_for_each(|r| {
+                    configs.get_mut(&r.name).unwrap().push_str("\n/routing bgp evpn");
+                    for tenant in node_tenants(r) {
+                        let route_targets = vni_args.route_targets(*as_num, tenant.l3vni)?;
+                        configs.get_mut(&r.name).unwrap().push_str(&format!(
+                            "\nadd{route_targets} instance=wg-mesh-bgp name=wg-mesh-evpn-{} vni={} vrf={} comment=mt-wg-meshconf",
+                            tenant.l3vni, tenant.l3vni, tenant.name
+                        ));
+                    }
+                    Ok::<(), anyhow::Error>(())
+                })?;
+            }
+
+            // Firewall
+            if *firewall {
+                let port_assignations = assign_ports(&records)?;
+
+                records.iter().for_each(|r| {
+                    let s = configs.get_mut(&r.name).unwrap();
+                    s.push_str(
+                        "\n\n/interface list\nremove [find comment=\"mt-wg-meshconf\"]\nadd name=wg-mesh comment=mt-wg-meshconf\n/interface list member\nremove [find comment=\"mt-wg-meshconf\"]",
+                    );
+                    for b in &records {
+                        if r.name != b.name {
+                            s.push_str(&format!(
+                                "\nadd interface={} list=wg-mesh comment=mt-wg-meshconf",
+                                b.interface
+                            ));
+                        }
+                    }
+                });
+
+                // Entries of the ebgp underlay are already in the list
+                records.iter().for_each(|r| {
+                    let s = configs.get_mut(&r.name).unwrap();
+                    s.push_str("\n/ip firewall address-list");
+                    if *underlay != Underlay::Ebgp {
+                        s.push_str("\nremove [find comment=\"mt-wg-meshconf\"]");
+                    }
+                    for b in &records {
+                        s.push_str(&format!(
+                            "\nadd address={} list=wg-mesh-loopbacks comment=mt-wg-meshconf",
+                            b.loopback
+                        ));
+                    }
+                });
+
+                records.iter().for_each(|r| {
+                    let s = configs.get_mut(&r.name).unwrap();
+                    s.push_str("\n/ip firewall filter\nremove [find comment=\"mt-wg-meshconf\"]");
+                    let ports = match (r.port_min, r.port_max) {
+                        (Some(port_min), Some(port_max)) => format!("{port_min}-{port_max}"),
+                        _ => records
+                            .iter()
+                            .filter(|b| r.name != b.name)
+                            .map(|b| port_assignations.get(&(r.name.clone(), b.name.clone())).unwrap().to_string())
+                            .collect::<Vec<_>>()
+                            .join(","),
+                    };
+                    let mut rules = vec![format!("dst-port={ports} protocol=udp")];
+                    if *ospf && *underlay == Underlay::Ospf {
+                        rules.push("in-interface-list=wg-mesh protocol=ospf".to_owned());
+                    }
+                    if *underlay == Underlay::Ebgp {
+                        rules.push("dst-port=179 in-interface-list=wg-mesh protocol=tcp".to_owned());
+                    }
+                    if *bfd {
+                        rules.push("dst-port=3784 in-interface-list=wg-mesh protocol=udp".to_owned());
+                        // Multihop sessions between loopbacks
+                        if *evpn {
+                            rules.push(
+                                "dst-port=4784 protocol=udp src-address-list=wg-mesh-loopbacks"
+                                    .to_owned(),
+                            );
+                        }
+                    }
+                    if *evpn {
+                        rules.push("dst-address-list=wg-mesh-loopbacks dst-port=179 protocol=tcp src-address-list=wg-mesh-loopbacks".to_owned());
+                        rules.push("dst-address-list=wg-mesh-loopbacks dst-port=4789 protocol=udp src-address-list=wg-mesh-loopbacks".to_owned());
+                    }
+                    // Each rule goes on top, added in reverse to keep their order
+                    for rule in rules.iter().rev() {
+                        s.push_str(&format!(
+                            "\nadd action=accept chain=input {rule} place-before=0 comment=mt-wg-meshconf"
+                        ));
+                    }
                 });
+            }
+
+            if *dnat {
+                if *dnat_return == DnatReturn::Mac {
+                    if !l3 {
+                        return Err(anyhow!(
+                            "the mac dnat return path needs evpn and the l3-evpn profile"
+                        ));
+                    }
+
+                    records.iter().for_each(|r| {
+                        configs.get_mut(&r.name).unwrap().push_str(
+                            "\n\n/interface bridge settings set use-ip-firewall=yes use-ip-firewall-for-vlan=yes",
+                        )
+                    });
+                } else if *evpn
+                    && let Some((vlan, _)) = vlan_members(&records)
+                        .into_iter()
+                        .find(|(_, members)| members.len() > 1)
+                {
+                    return Err(anyhow!(
+                        "the routed dnat return path only covers lans that aren't stretched, vlan {vlan} is stretched over evpn and needs --dnat-return mac"
+                    ));
+                }
 
                 records.iter().for_each(|r| {
                     configs
@@ -687,7 +3137,7 @@ fn main() -> Result<()> {
                         ))
                     }
                 });
-                // Add default routes on those tables
+                // Add default routes on those tables, over the l2 overlay or the tunnel
                 records
                     .iter()
                     .for_each(|r| configs.get_mut(&r.name).unwrap().push_str("\n/ip route"));
@@ -696,17 +3146,22 @@ fn main() -> Result<()> {
                         if r.name == peer.name {
                             continue;
                         }
-                        let Some(ips) = &peer.ifs_ips else {
-                            println!("warning: you need at least one unique gateway ip for the router to be able to dstnat");
-                            continue;
-                        };
-                        let Some(ip) = ips.first() else {
-                            println!("warning: you need at least one unique gateway ip for the router to be able to dstnat");
-                            continue;
+                        let gateway = match dnat_return {
+                            DnatReturn::Mac => {
+                                let Some(ip) = peer.ifs_ips.iter().flatten().next() else {
+                                    println!("warning: you need at least one unique gateway ip for the router to be able to dstnat");
+                                    continue;
+                                };
+                                ip.split("/").next().context("invalid gateway ip")?.to_owned()
+                            }
+                            DnatReturn::Routed => {
+                                let (_, remote) =
+                                    ptp_pairs.get(&(r.name.clone(), peer.name.clone())).unwrap();
+                                format!("{remote}%{}", peer.interface)
+                            }
                         };
-                        let ip = ip.split("/").next().context("invalid gateway ip")?;
                         configs.get_mut(&r.name).unwrap().push_str(&format!(
-                            "\nadd dst-address=0.0.0.0/0 gateway={ip} routing-table={} comment=mt-wg-meshconf", peer.interface));
+                            "\nadd dst-address=0.0.0.0/0 gateway={gateway} routing-table={} comment=mt-wg-meshconf", peer.interface));
                     }
                     Ok::<(), anyhow::Error>(())
                 })?;
@@ -723,15 +3178,31 @@ fn main() -> Result<()> {
                         if r.name == peer.name {
                             continue;
                         }
-                        let mac = bridge_macs.get(&peer.name).unwrap();
-                        configs.get_mut(&r.name).unwrap().push_str(&format!(
-                            "\nadd action=mark-connection chain=forward new-connection-mark={} src-mac-address={mac} comment=mt-wg-meshconf",
-                            peer.interface
-                        ));
-                        configs.get_mut(&r.name).unwrap().push_str(&format!(
-                            "\nadd action=mark-routing chain=prerouting connection-mark={} new-routing-mark={} comment=mt-wg-meshconf",
-                            peer.interface, peer.interface
-                        ))
+                        let s = configs.get_mut(&r.name).unwrap();
+                        match dnat_return {
+                            DnatReturn::Mac => {
+                                let mac = bridge_macs.get(&peer.name).unwrap();
+                                s.push_str(&format!(
+                                    "\nadd action=mark-connection chain=forward connection-state=new new-connection-mark={} src-mac-address={mac} comment=mt-wg-meshconf",
+                                    peer.interface
+                                ));
+                                s.push_str(&format!(
+                                    "\nadd action=mark-routing chain=prerouting connection-mark={} new-routing-mark={} comment=mt-wg-meshconf",
+                                    peer.interface, peer.interface
+                                ));
+                            }
+                            // The replies come from the lans, not from the tunnel
+                            DnatReturn::Routed => {
+                                s.push_str(&format!(
+                                    "\nadd action=mark-connection chain=prerouting connection-state=new dst-address-type=!local in-interface={} new-connection-mark={} comment=mt-wg-meshconf",
+                                    peer.interface, peer.interface
+                                ));
+                                s.push_str(&format!(
+                                    "\nadd action=mark-routing chain=prerouting connection-mark={} in-interface=!{} new-routing-mark={} comment=mt-wg-meshconf",
+                                    peer.interface, peer.interface, peer.interface
+                                ));
+                            }
+                        }
                     }
                 });
             }
@@ -740,23 +3211,62 @@ fn main() -> Result<()> {
         Some(Commands::NatInit) => {
             let mut wtr = csv::WriterBuilder::new()
                 .flexible(true)
+                .has_headers(false)
                 .from_path(cli.nat_filename.clone())
                 .context(format!(
                     "Failed to write csv to {}",
                     cli.nat_filename.display()
                 ))?;
-            wtr.serialize(NatRecord::Simple(SimpleNat {
-                dest_ip: IpAddr::V4(Ipv4Addr::new(10, 10, 10, 10)),
-                protocol: Some("tcp".to_owned()),
-                dest_port: Some(400),
-                rewrite_ip: IpAddr::V4(Ipv4Addr::new(10, 10, 10, 10)),
-                rewrite_port: None,
-                comment: "this nat".to_owned(),
-            }))?;
-            wtr.serialize(NatRecord::Custom(CustomNat {
-                custom_cmd: "add ....".to_owned(),
-                comment: "this nat".to_owned(),
-            }))?;
+            wtr.write_record([
+                "type",
+                "comment",
+                "dest_ip",
+                "protocol",
+                "dest_port",
+                "rewrite_ip",
+                "rewrite_port",
+                "src_address",
+                "src_address_list",
+                "in_interface",
+                "in_interface_list",
+                "nodes",
+            ])?;
+            for nat_record in [
+                NatRecord::Simple(SimpleNat {
+                    dest_ip: IpAddr::V4(Ipv4Addr::new(10, 10, 10, 10)),
+                    protocol: Some("tcp".to_owned()),
+                    dest_port: Some("400".parse()?),
+                    rewrite_ip: IpAddr::V4(Ipv4Addr::new(10, 10, 10, 10)),
+                    rewrite_port: None,
+                    comment: "this nat".to_owned(),
+                    src_address: None,
+                    src_address_list: None,
+                    in_interface: None,
+                    in_interface_list: None,
+                    nodes: None,
+                }),
+                NatRecord::Source(SourceNat {
+                    comment: "this snat".to_owned(),
+                    action: "masquerade".to_owned(),
+                    src_address: "10.10.10.0/24".to_owned(),
+                    out_interface: Some("ether1".to_owned()),
+                    out_interface_list: None,
+                    to_address: None,
+                    nodes: None,
+                }),
+                NatRecord::Custom(CustomNat {
+                    custom_cmd: "add ....".to_owned(),
+                    comment: "this nat".to_owned(),
+                    nodes: None,
+                }),
+            ] {
+                wtr.write_field(nat_record.kind())?;
+                match nat_record {
+                    NatRecord::Simple(n) => wtr.serialize(n)?,
+                    NatRecord::Source(n) => wtr.serialize(n)?,
+                    NatRecord::Custom(n) => wtr.serialize(n)?,
+                }
+            }
             println!(
                 "{} was created.",
                 cli.nat_filename.to_str().context("filename error")?
@@ -764,43 +3274,31 @@ fn main() -> Result<()> {
             wtr.flush()
                 .context(format!("Failed to write to {}", cli.nat_filename.display()))?;
         }
-        Some(Commands::NatGen) => {
-            let mut configs = HashMap::new();
-
-            let mut rdr = csv::Reader::from_path(cli.filename.clone()).context(format!(
-                "Failed to read csv from {}",
-                cli.filename.display()
-            ))?;
+        Some(Commands::NatCheck) => {
+            let records = read_records(&cli.filename)?;
 
-            let records: Vec<Record> = rdr.deserialize().collect::<Result<Vec<_>, _>>()?;
-
-            let mut rdr = csv::ReaderBuilder::new()
-                .flexible(true)
-                .from_path(cli.nat_filename.clone())
-                .context(format!(
-                    "Failed to read csv from {}",
-                    cli.nat_filename.display()
-                ))?;
+            let nat_records = read_nat_records(&cli)?;
+            check_nat_records(&nat_records, &records)?;
+            println!(
+                "{}: {} nat records are valid",
+                cli.nat_filename.display(),
+                nat_records.len()
+            );
+        }
+        Some(Commands::NatGen { hairpin, filter }) => {
+            let mut configs = HashMap::new();
 
-            let mut nat_records: Vec<NatRecord> = Vec::new(); //rdr.deserialize().collect::<Result<Vec<_>, _>>()?;
+            let records = read_records(&cli.filename)?;
 
-            for r in rdr.records() {
-                let r = r?;
-                if r[1].starts_with("add ") {
-                    let r: CustomNat = r.deserialize(None)?;
-                    nat_records.push(NatRecord::Custom(r));
-                } else {
-                    let r: SimpleNat = r.deserialize(None)?;
-                    nat_records.push(NatRecord::Simple(r));
-                }
-            }
+            let nat_records = read_nat_records(&cli)?;
+            check_nat_records(&nat_records, &records)?;
 
             // Create config entries
             for r in &records {
                 configs.insert(
                     r.name.clone(),
                     format!(
-                        "# {} DNAT config generated by mt-wg-meshconf at {}",
+                        "# {} NAT config generated by mt-wg-meshconf at {}",
                         r.name,
                         chrono::Local::now().format("%Y-%m-%d %H:%M:%S")
                     ),
@@ -814,38 +3312,451 @@ fn main() -> Result<()> {
                     .push_str("\n\n/ip firewall nat\nremove [find comment=\"mt-wg-nat\"]")
             });
 
+            // Hairpin nat for the clients of the mesh vlans, the list is removed
+            // even without --hairpin, for the entries of previous runs
+            records.iter().try_for_each(|r| {
+                let s = configs.get_mut(&r.name).unwrap();
+                s.push_str("\n/ip firewall address-list\nremove [find comment=\"mt-wg-nat\"]");
+                if *hairpin {
+                    for lan in vlan_subnets(&records)? {
+                        s.push_str(&format!(
+                            "\nadd address={lan} list=wg-mesh-nat-lans comment=mt-wg-nat"
+                        ));
+                    }
+                }
+                s.push_str("\n/ip firewall nat");
+                Ok::<(), anyhow::Error>(())
+            })?;
+
             records.iter().for_each(|r| {
-                nat_records.iter().for_each(|n| {
-                    let s = configs.get_mut(&r.name).unwrap();
-                    match n {
-                        NatRecord::Simple(simple_nat) => {
-                            s.push_str(&format!(
-                                "\nadd action=dst-nat chain=dstnat dst-address={} to-addresses={}",
-                                simple_nat.dest_ip, simple_nat.rewrite_ip
-                            ));
-                            if let Some(protocol) = &simple_nat.protocol {
-                                s.push_str(&format!(" protocol={protocol}"));
+                let mut filters = vec![];
+                nat_records
+                    .iter()
+                    .filter(|n| n.applies_to(r))
+                    .for_each(|n| {
+                        let s = configs.get_mut(&r.name).unwrap();
+                        match n {
+                            NatRecord::Simple(simple_nat) => {
+                                s.push_str(&format!(
+                                    "\nadd action=dst-nat chain=dstnat {} comment=mt-wg-nat",
+                                    simple_nat.dst_nat(true)
+                                ));
+                                filters.push(simple_nat.forward_filter(true));
+                                if *hairpin {
+                                    // Clients of the mesh don't come from the in-interface
+                                    if simple_nat.src_address.is_none()
+                                        && simple_nat.src_address_list.is_none()
+                                        && (simple_nat.in_interface.is_some()
+                                            || simple_nat.in_interface_list.is_some())
+                                    {
+                                        s.push_str(&format!(
+                                            "\nadd action=dst-nat chain=dstnat {} src-address-list=wg-mesh-nat-lans comment=mt-wg-nat",
+                                            simple_nat.dst_nat(false)
+                                        ));
+                                        filters.push(format!(
+                                            "{} src-address-list=wg-mesh-nat-lans",
+                                            simple_nat.forward_filter(false)
+                                        ));
+                                    }
+                                    s.push_str(&format!(
+                                        "\nadd action=masquerade chain=srcnat dst-address={}{} src-address-list=wg-mesh-nat-lans comment=mt-wg-nat",
+                                        simple_nat.rewrite_ip,
+                                        simple_nat.rewritten_ports()
+                                    ));
+                                }
                             }
-                            if let Some(rewrite_port) = &simple_nat.rewrite_port {
-                                s.push_str(&format!(" to-ports={rewrite_port}"));
+                            NatRecord::Source(source_nat) => {
+                                s.push_str(&format!(
+                                    "\nadd {} comment=mt-wg-nat",
+                                    source_nat.rule()
+                                ));
                             }
-                            if let Some(dest_port) = &simple_nat.dest_port {
-                                s.push_str(&format!(" dst-port={dest_port}"));
+                            NatRecord::Custom(custom_nat) => {
+                                s.push_str(&format!(
+                                    "\n{} comment=mt-wg-nat",
+                                    custom_nat.custom_cmd
+                                ));
                             }
-                            s.push_str(" comment=mt-wg-nat");
                         }
-                        NatRecord::Custom(custom_nat) => {
-                            configs.get_mut(&r.name).unwrap().push_str(&format!(
-                                "\n{} comment=mt-wg-nat",
-                                custom_nat.custom_cmd
-                            ));
+                    });
+                // Removed even without --filter, for the rules of previous runs
+                let s = configs.get_mut(&r.name).unwrap();
+                s.push_str("\n/ip firewall filter\nremove [find comment=\"mt-wg-nat\"]");
+                if *filter {
+                    // Forwards sharing a rewrite share their filter
+                    let mut rules: Vec<String> = vec![];
+                    for rule in filters {
+                        if !rules.contains(&rule) {
+                            rules.push(rule);
                         }
                     }
-                });
+                    // Each rule goes on top, added in reverse to keep their order
+                    for rule in rules.iter().rev() {
+                        s.push_str(&format!(
+                            "\nadd action=accept chain=forward {rule} place-before=0 comment=mt-wg-nat"
+                        ));
+                    }
+                }
             });
             export_configs(&cli, configs)?;
         }
+        Some(Commands::GenPortForwards) => {
+            let mut configs: HashMap<String, String> = HashMap::new();
+
+            let records = read_records(&cli.filename)?;
+
+            let port_assignations = assign_ports(&records)?;
+
+            // One config per public endpoint, for the router doing the port forwarding
+            for server in &records {
+                let Some(lan_address) = server.lan_address else {
+                    continue;
+                };
+                let endpoint = server
+                    .endpoint
+                    .clone()
+                    .context(format!("{}: no endpoint address", server.name))?;
+                let port_min = server.port_min.context("no min port set")?;
+                let s = configs.entry(endpoint.host.to_string()).or_insert_with(|| {
+                    format!(
+                        "# {} port forwarding config generated by mt-wg-meshconf at {}\n\n/ip firewall nat\nremove [find comment=\"mt-wg-meshconf-fwd\"]",
+                        endpoint.host,
+                        chrono::Local::now().format("%Y-%m-%d %H:%M:%S")
+                    )
+                });
+                for peer in &records {
+                    if server.name == peer.name {
+                        continue;
+                    }
+                    let port = port_assignations
+                        .get(&(server.name.clone(), peer.name.clone()))
+                        .unwrap();
+                    let public_port = endpoint.public_port(*port, port_min).context(format!(
+                        "{}: endpoint port of {endpoint} is too high for its listening ports",
+                        server.name
+                    ))?;
+                    s.push_str("\nadd action=dst-nat chain=dstnat");
+                    // Hostnames can't be matched on, forward the port on every address
+                    if let EndpointHost::Ip(ip) = &endpoint.host {
+                        s.push_str(&format!(" dst-address={ip}"));
+                    }
+                    s.push_str(&format!(
+                        " protocol=udp dst-port={public_port} to-addresses={lan_address} to-ports={port} comment=mt-wg-meshconf-fwd"
+                    ));
+                }
+            }
+
+            // Every node sharing a forwarded endpoint needs a lan address
+            for r in &records {
+                if let Some(endpoint) = &r.endpoint
+                    && configs.contains_key(&endpoint.host.to_string())
+                    && r.lan_address.is_none()
+                {
+                    return Err(anyhow!(
+                        "{}: no lan_address set but endpoint {endpoint} is port forwarded",
+                        r.name
+                    ));
+                }
+            }
+            export_configs(&cli, configs)?;
+        }
         None => {}
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v4(s: &str) -> Ipv4Addr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn endpoint_parsing() {
+        let endpoint: Endpoint = "1.2.3.4:5000".parse().unwrap();
+        assert_eq!(endpoint.host, EndpointHost::Ip("1.2.3.4".parse().unwrap()));
+        assert_eq!(endpoint.port, Some(5000));
+
+        let endpoint: Endpoint = "[2001:db8::1]:5000".parse().unwrap();
+        assert_eq!(
+            endpoint.host,
+            EndpointHost::Ip("2001:db8::1".parse().unwrap())
+        );
+        assert_eq!(endpoint.port, Some(5000));
+
+        let endpoint: Endpoint = "2001:db8::1".parse().unwrap();
+        assert_eq!(endpoint.port, None);
+
+        let endpoint: Endpoint = "vpn.example.com.".parse().unwrap();
+        assert_eq!(
+            endpoint.host,
+            EndpointHost::Hostname("vpn.example.com".to_owned())
+        );
+        assert_eq!(endpoint.port, None);
+
+        for invalid in [
+            "1.2.3.4:99999",
+            "[2001:db8::1",
+            "[2001:db8::1]5000",
+            "-bad.example.com",
+            "1.2.3.400",
+        ] {
+            assert!(invalid.parse::<Endpoint>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn public_port_overflow() {
+        let endpoint: Endpoint = "1.2.3.4:65534".parse().unwrap();
+        assert_eq!(endpoint.public_port(1001, 1000), Some(65535));
+        assert_eq!(endpoint.public_port(1002, 1000), None);
+
+        let endpoint: Endpoint = "1.2.3.4".parse().unwrap();
+        assert_eq!(endpoint.public_port(1002, 1000), Some(1002));
+    }
+
+    #[test]
+    fn ports_parsing() {
+        let ports: Ports = "80;443, 8000-8080".parse().unwrap();
+        assert_eq!(ports, Ports(vec![(80, 80), (443, 443), (8000, 8080)]));
+        assert_eq!(ports.to_string(), "80,443,8000-8080");
+
+        for invalid in ["", "80;", "8080-8000", "http", "70000"] {
+            assert!(invalid.parse::<Ports>().is_err(), "{invalid}");
+        }
+    }
+
+    #[serde_as]
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Lists {
+        #[serde_as(as = "Option<SemicolonList>")]
+        vlan: Option<Vec<u16>>,
+        #[serde_as(as = "Option<SemicolonList>")]
+        ifs: Option<Vec<String>>,
+    }
+
+    #[test]
+    fn semicolon_list_formats() {
+        let lists = |vlan: Option<Vec<u16>>, ifs: Option<Vec<&str>>| Lists {
+            vlan,
+            ifs: ifs.map(|ifs| ifs.into_iter().map(|i| i.to_owned()).collect()),
+        };
+
+        let mut rdr = csv::Reader::from_reader(
+            "vlan,ifs\n100;101,ether2;ether3\n100,\n,true\n,1.5\n".as_bytes(),
+        );
+        let rows = rdr
+            .deserialize()
+            .collect::<Result<Vec<Lists>, _>>()
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                lists(Some(vec![100, 101]), Some(vec!["ether2", "ether3"])),
+                lists(Some(vec![100]), None),
+                lists(None, Some(vec!["true"])),
+                lists(None, Some(vec!["1.5"])),
+            ]
+        );
+
+        let mut wtr = csv::Writer::from_writer(vec![]);
+        wtr.serialize(&rows[0]).unwrap();
+        assert_eq!(
+            String::from_utf8(wtr.into_inner().unwrap()).unwrap(),
+            "vlan,ifs\n100;101,ether2;ether3\n"
+        );
+
+        let toml: Lists = toml::from_str("vlan = [100, 101]\nifs = [\"ether2\"]").unwrap();
+        assert_eq!(toml, lists(Some(vec![100, 101]), Some(vec!["ether2"])));
+
+        let yaml: Lists = serde_yaml_ng::from_str("vlan: [100]\nifs: ether2;ether3").unwrap();
+        assert_eq!(yaml, lists(Some(vec![100]), Some(vec!["ether2", "ether3"])));
+
+        assert!(toml::from_str::<Lists>("vlan = [\"x\"]").is_err());
+    }
+
+    #[test]
+    fn dhcp_ranges_split_evenly() {
+        let ranges = dhcp_ranges(parse_prefix("10.0.0.0/24").unwrap(), 2, &[]).unwrap();
+        assert_eq!(
+            ranges,
+            vec![
+                vec![(v4("10.0.0.1"), v4("10.0.0.127"))],
+                vec![(v4("10.0.0.128"), v4("10.0.0.254"))],
+            ]
+        );
+    }
+
+    #[test]
+    fn dhcp_ranges_skip_reserved() {
+        let reserved = ["10.0.0.1", "10.0.0.50"].map(|ip| ip.parse().unwrap());
+        let ranges = dhcp_ranges(parse_prefix("10.0.0.0/24").unwrap(), 1, &reserved).unwrap();
+        assert_eq!(
+            ranges,
+            vec![vec![
+                (v4("10.0.0.2"), v4("10.0.0.49")),
+                (v4("10.0.0.51"), v4("10.0.0.254")),
+            ]]
+        );
+    }
+
+    #[test]
+    fn dhcp_ranges_need_nodes() {
+        assert!(dhcp_ranges(parse_prefix("10.0.0.0/24").unwrap(), 0, &[]).is_err());
+    }
+
+    #[test]
+    fn dhcp_ranges_reject_huge_subnets() {
+        assert!(dhcp_ranges(parse_prefix("0.0.0.0/0").unwrap(), 2, &[]).is_err());
+    }
+
+    #[test]
+    fn bridge_and_anycast_macs_differ() {
+        let loopback = "119.103.0.100".parse().unwrap();
+        assert_ne!(bridge_mac(loopback), anycast_mac(100));
+        assert_eq!(bridge_mac(loopback).to_string(), "0A:6D:77:67:00:64");
+    }
+
+    /// Nat records of a csv written to a temporary file
+    fn read_nat_csv(name: &str, content: &str) -> Result<Vec<NatRecord>> {
+        let path =
+            std::env::temp_dir().join(format!("mt-wg-meshconf-{}-{name}.csv", std::process::id()));
+        fs::write(&path, content).unwrap();
+        let cli = Cli::parse_from(["mt-wg-meshconf", "--nat-filename", path.to_str().unwrap()]);
+        let nat_records = read_nat_records(&cli);
+        fs::remove_file(&path).unwrap();
+        nat_records
+    }
+
+    #[test]
+    fn nat_records_columns() {
+        const HEADER: &str = "type,comment,dest_ip,protocol,dest_port,rewrite_ip\n";
+
+        let nat_records = read_nat_csv(
+            "short",
+            &format!(
+                "{HEADER}simple,web,1.2.3.4,tcp,80,192.168.0.10\nsnat,out,masquerade,10.0.0.0/8\ncustom,c,add x\n"
+            ),
+        )
+        .unwrap();
+        assert!(matches!(
+            &nat_records[..],
+            [
+                NatRecord::Simple(SimpleNat {
+                    rewrite_port: None,
+                    in_interface: None,
+                    ..
+                }),
+                NatRecord::Source(SourceNat {
+                    out_interface: None,
+                    to_address: None,
+                    ..
+                }),
+                NatRecord::Custom(CustomNat { nodes: None, .. }),
+            ]
+        ));
+
+        let err = read_nat_csv(
+            "long",
+            &format!("{HEADER}custom,c,add x\nsimple,web,1.2.3.4,tcp,80,10.0.0.1,,,,,,,extra\n"),
+        )
+        .unwrap_err();
+        assert!(
+            err.to_string().ends_with(
+                "-long.csv:3 simple records have at most 11 columns after the type, found 12"
+            ),
+            "{err}"
+        );
+
+        let err = read_nat_csv(
+            "unknown",
+            &format!("{HEADER}custom,c,add x\ncustom,d,add y\ndnat,web,1.2.3.4\n"),
+        )
+        .unwrap_err();
+        assert!(
+            err.to_string().ends_with(
+                "-unknown.csv:4 unknown record type \"dnat\", expected one of simple, snat, custom"
+            ),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn vni_check() {
+        let vni_args = VniArgs::default();
+        assert!(vni_args.check([100, 101, 100]).is_ok());
+
+        let vni_args = VniArgs {
+            vni_map: vec![(100, 1000101)],
+            ..Default::default()
+        };
+        assert_eq!(
+            vni_args.check([100, 101]).unwrap_err().to_string(),
+            "vlans 100 and 101 both use vni 1000101"
+        );
+
+        let vni_args = VniArgs {
+            vni_base: Some(MAX_VNI - 100),
+            ..Default::default()
+        };
+        assert!(vni_args.check([100]).is_ok());
+        assert_eq!(
+            vni_args.check([101]).unwrap_err().to_string(),
+            "vlan 101: vni 16777216 is outside of 1-16777215"
+        );
+
+        let vni_args = VniArgs {
+            vni_map: vec![(100, 0)],
+            ..Default::default()
+        };
+        assert!(vni_args.check([100]).is_err());
+    }
+
+    #[test]
+    fn set_fields_values() {
+        let mut rdr = csv::Reader::from_reader(
+            "name,interface,endpoint,endpoint_v6,loopback,port_min,port_max,keepalive,privkey,lan_address,underlay_mtu,region,role,asn,tags,vlan,vlan_ifs,ifs_ips\n\
+             node1,node1,10.200.0.10,,10.69.0.10,1000,1050,25,MAK5Lp4H7d4xjXzPr35w4Lm4rC8KaeipVyaxQjlvCkg=,,,,,,,100,ether2,\n"
+                .as_bytes(),
+        );
+        let record: Record = rdr.deserialize().next().unwrap().unwrap();
+
+        let set = |fields: &[&str]| {
+            set_fields(
+                &record,
+                &fields.iter().map(|f| f.to_string()).collect::<Vec<_>>(),
+            )
+        };
+        let updated = set(&[
+            "port_min=2000",
+            "tags=edge;true",
+            "vlan=100;101",
+            "endpoint=",
+        ])
+        .unwrap();
+        assert_eq!(updated.port_min, Some(2000));
+        assert_eq!(updated.port_max, Some(1050));
+        assert_eq!(
+            updated.tags,
+            Some(vec!["edge".to_owned(), "true".to_owned()])
+        );
+        assert_eq!(updated.vlan, Some(vec![100, 101]));
+        assert_eq!(updated.endpoint, None);
+        assert_eq!(updated.loopback, record.loopback);
+
+        assert_eq!(
+            set(&["port_min"]).unwrap_err().to_string(),
+            "port_min: expected field=value"
+        );
+        assert_eq!(
+            set(&["mtu=1400"]).unwrap_err().to_string(),
+            "mtu isn't a node field"
+        );
+        assert_eq!(
+            set(&["port_min=high"]).unwrap_err().to_string(),
+            "node1: invalid value"
+        );
+    }
+}